@@ -0,0 +1,125 @@
+//! Position lookups and rendering on large generated sources, where every line
+//! has multi-byte characters in it.
+//!
+//! `charpos/linear` walks the multi-byte characters the way `CodeMap` used to,
+//! for comparison with the binary search `charpos/codemap` does now.
+
+#[macro_use]
+extern crate criterion;
+extern crate error_reporter;
+
+use std::rc::Rc;
+
+use criterion::{BenchmarkId, Criterion};
+
+use error_reporter::{render_succinct, BytePos, CharPos, CodeMap, CompilerMessage, FileMap, Level,
+                     Span, NO_EXPANSION};
+
+const SIZES: &[usize] = &[1_000, 10_000, 100_000];
+
+fn generated_source(lines: usize) -> String {
+    (0..lines)
+        .map(|i| format!("    let 変数{} = \"é → {}\"; // ünïcödé\n", i, i))
+        .collect()
+}
+
+/// Positions spread evenly through the file, each at the start of a line.
+fn positions(fm: &FileMap, count: usize) -> Vec<BytePos> {
+    let lines = fm.lines.borrow();
+    (0..count).map(|i| lines[i * lines.len() / count]).collect()
+}
+
+/// The char position of `pos` found by walking every multi-byte character
+/// before it.
+fn linear_charpos(fm: &FileMap, pos: BytePos) -> CharPos {
+    let mut extra_bytes = 0;
+    for mbc in fm.multibyte_chars().iter() {
+        if mbc.pos < pos {
+            extra_bytes += mbc.bytes - 1;
+        } else {
+            break;
+        }
+    }
+    CharPos((pos - fm.start_pos).0 as usize - extra_bytes)
+}
+
+fn charpos(c: &mut Criterion) {
+    let mut group = c.benchmark_group("charpos");
+    for &size in SIZES {
+        let cm = CodeMap::new();
+        let fm = cm.new_filemap_and_lines("gen.rs", &generated_source(size));
+        let positions = positions(&fm, 100);
+
+        group.bench_with_input(BenchmarkId::new("linear", size), &positions, |b, positions| {
+            b.iter(|| {
+                for &pos in positions {
+                    criterion::black_box(linear_charpos(&fm, pos));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("codemap", size), &positions, |b, positions| {
+            b.iter(|| {
+                for &pos in positions {
+                    criterion::black_box(cm.bytepos_to_file_charpos(pos));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn lookup_char_pos(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup_char_pos");
+    for &size in SIZES {
+        let cm = CodeMap::new();
+        // Several files, so the file has to be looked up as well
+        for i in 0..10 {
+            cm.new_filemap_and_lines(&format!("gen{}.rs", i), &generated_source(size / 10));
+        }
+        let fm = cm.get_filemap("gen9.rs");
+        let positions = positions(&fm, 100);
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &positions, |b, positions| {
+            b.iter(|| {
+                for &pos in positions {
+                    criterion::black_box(cm.lookup_char_pos(pos));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render_succinct");
+    for &size in SIZES {
+        let cm = Rc::new(CodeMap::new());
+        let fm = cm.new_filemap_and_lines("gen.rs", &generated_source(size));
+        let spans: Vec<Span> = positions(&fm, 100)
+            .into_iter()
+            .map(|lo| {
+                Span {
+                    lo: lo + BytePos(4),
+                    hi: lo + BytePos(7),
+                    expn_id: NO_EXPANSION,
+                }
+            })
+            .collect();
+        let mut msg = CompilerMessage::new(Level::Error,
+                                           String::from("unused variable"),
+                                           spans[0],
+                                           None,
+                                           cm.clone());
+        for &span in &spans {
+            msg.span_label(span, Some(String::from("never used")));
+        }
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &msg, |b, msg| {
+            b.iter(|| render_succinct(msg))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, charpos, lookup_char_pos, render);
+criterion_main!(benches);
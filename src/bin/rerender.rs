@@ -0,0 +1,194 @@
+//! Renders the JSON diagnostics of rustc or cargo again, from a file or from
+//! stdin, eg)
+//!
+//!     cargo build --message-format=json 2>/dev/null > build.log
+//!     rerender --color=always build.log
+
+extern crate error_reporter;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+
+use error_reporter::{parse_diagnostics, to_compiler_messages, CodeMap, ColorConfig, Emitter,
+                     EmitterWriter, FileLoader, JsonEmitter, RealFileLoader, SarifEmitter,
+                     Theme, render_html_page};
+
+const USAGE: &str = "\
+Usage: rerender [OPTIONS] [FILE]
+
+Renders the JSON diagnostics in FILE, or in stdin, as rustc would.
+
+Options:
+    --color WHEN     color the output: auto, always or never (default: auto)
+    --format FORMAT  human, short, json, sarif or html (default: human)
+    --root DIR       look for the source files relative to DIR
+    --theme THEME    color the human and short output with a built-in theme
+                     or a theme file (default: default)
+    -h, --help       print this message";
+
+enum Format {
+    Human,
+    Short,
+    Json,
+    Sarif,
+    Html,
+}
+
+struct Options {
+    color: ColorConfig,
+    format: Format,
+    root: Option<PathBuf>,
+    theme: Theme,
+    input: Option<PathBuf>,
+}
+
+/// Loads files relative to another directory, keeping their names.
+struct RootedFileLoader(PathBuf);
+
+impl FileLoader for RootedFileLoader {
+    fn file_exists(&self, path: &Path) -> bool {
+        RealFileLoader.file_exists(&self.0.join(path))
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        RealFileLoader.read_file(&self.0.join(path))
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        color: ColorConfig::Auto,
+        format: Format::Human,
+        root: None,
+        theme: Theme::default(),
+        input: None,
+    };
+
+    while let Some(arg) = args.next() {
+        // Take `--flag=value` as well as `--flag value`
+        let (flag, value) = match arg.find('=') {
+            Some(idx) if arg.starts_with("--") => {
+                (arg[..idx].to_owned(), Some(arg[idx + 1..].to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = |flag: &str| {
+            value.clone().or_else(|| args.next()).ok_or(format!("{} needs a value", flag))
+        };
+        match &flag[..] {
+            "-h" | "--help" => return Err(String::new()),
+            "--color" => {
+                options.color = match &value("--color")?[..] {
+                    "auto" => ColorConfig::Auto,
+                    "always" => ColorConfig::Always,
+                    "never" => ColorConfig::Never,
+                    other => return Err(format!("unknown color setting `{}`", other)),
+                }
+            }
+            "--format" => {
+                options.format = match &value("--format")?[..] {
+                    "human" => Format::Human,
+                    "short" => Format::Short,
+                    "json" => Format::Json,
+                    "sarif" => Format::Sarif,
+                    "html" => Format::Html,
+                    other => return Err(format!("unknown format `{}`", other)),
+                }
+            }
+            "--root" => options.root = Some(PathBuf::from(value("--root")?)),
+            "--theme" => {
+                let theme = value("--theme")?;
+                options.theme = match Theme::builtin(&theme) {
+                    Some(theme) => theme,
+                    None => Theme::load(Path::new(&theme)).map_err(|err| {
+                        format!("can't load theme `{}`: {}", theme, err)
+                    })?,
+                }
+            }
+            _ if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("unknown option `{}`", flag))
+            }
+            _ if options.input.is_some() => return Err(String::from("more than one input file")),
+            _ => options.input = Some(PathBuf::from(arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let mut input = String::new();
+    match options.input {
+        Some(ref path) if path != Path::new("-") => {
+            input = fs::read_to_string(path)?;
+        }
+        _ => {
+            io::stdin().read_to_string(&mut input)?;
+        }
+    }
+
+    let cm = Rc::new(match options.root {
+        Some(root) => CodeMap::with_file_loader(Box::new(RootedFileLoader(root))),
+        None => CodeMap::new(),
+    });
+    let messages = to_compiler_messages(&cm, &parse_diagnostics(&input));
+
+    let mut emitter: Box<dyn Emitter> = match options.format {
+        Format::Human => {
+            let mut emitter = EmitterWriter::stdout(options.color);
+            emitter.set_theme(options.theme);
+            Box::new(emitter)
+        }
+        Format::Short => {
+            let mut emitter = EmitterWriter::stdout(options.color);
+            emitter.set_theme(options.theme);
+            emitter.set_short_message(true);
+            Box::new(emitter)
+        }
+        Format::Json => Box::new(JsonEmitter::stdout()),
+        Format::Sarif => {
+            // The log is a single document, rather than a line per message
+            let mut emitter = SarifEmitter::stdout("rustc");
+            for msg in &messages {
+                emitter.emit(msg)?;
+            }
+            return emitter.finish();
+        }
+        Format::Html => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(render_html_page("Diagnostics", &messages).as_bytes())?;
+            return stdout.flush();
+        }
+    };
+    for msg in &messages {
+        emitter.emit(msg)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            if err.is_empty() {
+                println!("{}", USAGE);
+                return;
+            }
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(options) {
+        // Nobody is left to tell when the output was closed early
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    }
+}
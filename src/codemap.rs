@@ -19,14 +19,14 @@
 
 pub use self::ExpnFormat::*;
 
-use std::cell::{Cell, RefCell};
+use std::cell::{Cell, Ref, RefCell};
 use std::ops::{Add, Sub};
 use std::path::Path;
 use std::rc::Rc;
 use std::cmp;
 
 use std::{fmt, fs};
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Name(pub u32);
@@ -133,7 +133,7 @@ pub struct Span {
 ///   the error, and would be rendered with `^^^`.
 /// - they can have a *label*. In this case, the label is written next
 ///   to the mark in the snippet when we render.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct MultiSpan {
     primary_spans: Vec<Span>,
     span_labels: Vec<(Span, String)>,
@@ -396,6 +396,16 @@ pub struct FileMapAndBytePos {
     pub pos: BytePos,
 }
 
+/// A position as the Language Server Protocol counts it, where the
+/// characters outside the Basic Multilingual Plane take two columns
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Utf16Pos {
+    /// The (0-based) line number
+    pub line: usize,
+    /// The (0-based) column offset, in UTF-16 code units
+    pub col: usize,
+}
+
 
 // _____________________________________________________________________________
 // ExpnFormat, NameAndSpan, ExpnInfo, ExpnId
@@ -434,7 +444,7 @@ impl NameAndSpan {
 }
 
 /// Extra information for tracking spans of macro and syntax sugar expansion
-#[derive(Hash, Debug)]
+#[derive(Clone, Hash, Debug)]
 pub struct ExpnInfo {
     /// The location of the actual macro invocation or syntax sugar , e.g.
     /// `let x = foo!();` or `if let Some(y) = x {}`
@@ -513,8 +523,15 @@ pub struct FileMap {
     pub end_pos: BytePos,
     /// Locations of lines beginnings in the source code
     pub lines: RefCell<Vec<BytePos>>,
-    /// Locations of multi-byte characters in the source code
-    pub multibyte_chars: RefCell<Vec<MultiByteChar>>,
+    /// Locations of multi-byte characters in the source code, only changed
+    /// through `record_multibyte_char` and `set_multibyte_chars` so that
+    /// `multibyte_extra_bytes` stays in step
+    multibyte_chars: RefCell<Vec<MultiByteChar>>,
+    /// Running totals of the bytes past the first taken up by each of
+    /// `multibyte_chars` and the ones before it. Catches up with
+    /// `multibyte_chars` as characters are recorded, and is cleared when
+    /// they are replaced.
+    multibyte_extra_bytes: RefCell<Vec<usize>>,
 }
 
 impl fmt::Debug for FileMap {
@@ -554,16 +571,99 @@ impl FileMap {
                     // be parsing when we call this function and thus the current
                     // line is the last one we have line info for.
                     let slice = &src[begin..];
-                    match slice.find('\n') {
+                    let line = match slice.find('\n') {
                         Some(e) => &slice[..e],
                         None => slice,
-                    }
+                    };
+                    // Leave out the `\r` of a `\r\n` line ending
+                    line.strip_suffix('\r').unwrap_or(line)
                 })
             }
             None => None,
         }
     }
 
+    /// Scans the source for the beginnings of lines and for multi-byte
+    /// characters, replacing whatever was recorded before. This takes the
+    /// place of a lexer calling `next_line` and `record_multibyte_char` as it
+    /// goes. Only `\n` ends a line, so `\r\n` line endings work as well.
+    pub fn analyze_source(&self) {
+        let src = match self.src {
+            Some(ref src) => src,
+            None => return,
+        };
+
+        let (lines, multibyte_chars) = analyze_source(src, self.start_pos);
+        *self.lines.borrow_mut() = lines;
+        self.set_multibyte_chars(multibyte_chars);
+    }
+
+    /// The multi-byte characters recorded so far, in the order of their
+    /// positions.
+    pub fn multibyte_chars(&self) -> Ref<'_, Vec<MultiByteChar>> {
+        self.multibyte_chars.borrow()
+    }
+
+    /// Replaces the multi-byte characters recorded so far.
+    pub fn set_multibyte_chars(&self, multibyte_chars: Vec<MultiByteChar>) {
+        *self.multibyte_chars.borrow_mut() = multibyte_chars;
+        self.multibyte_extra_bytes.borrow_mut().clear();
+    }
+
+    /// The number of bytes past the first taken up by the multi-byte
+    /// characters before `pos`, found by binary search.
+    fn extra_bytes_before(&self, pos: BytePos) -> usize {
+        let mbcs = self.multibyte_chars.borrow();
+        let mut totals = self.multibyte_extra_bytes.borrow_mut();
+        while totals.len() < mbcs.len() {
+            let total = totals.last().cloned().unwrap_or(0) + mbcs[totals.len()].bytes - 1;
+            totals.push(total);
+        }
+
+        match mbcs.partition_point(|mbc| mbc.pos < pos) {
+            0 => 0,
+            count => {
+                // We should never see a byte position in the middle of a
+                // character
+                let mbc = mbcs[count - 1];
+                assert!(pos.to_usize() >= mbc.pos.to_usize() + mbc.bytes);
+                totals[count - 1]
+            }
+        }
+    }
+
+    /// The length of the text between `lo` and `hi` in UTF-16 code units.
+    fn utf16_len(&self, lo: BytePos, hi: BytePos) -> usize {
+        let chars = (hi - lo).to_usize() - (self.extra_bytes_before(hi) -
+                                            self.extra_bytes_before(lo));
+        let mbcs = self.multibyte_chars.borrow();
+        let first = mbcs.partition_point(|mbc| mbc.pos < lo);
+        let last = mbcs.partition_point(|mbc| mbc.pos < hi);
+        chars + mbcs[first..last].iter().filter(|mbc| mbc.bytes == 4).count()
+    }
+
+    /// The position `col` UTF-16 code units after `line_start`, kept before
+    /// `line_end`. A column in the middle of a character goes to its start.
+    fn utf16_col_to_byte_pos(&self, line_start: BytePos, line_end: BytePos, col: usize) -> BytePos {
+        let mbcs = self.multibyte_chars.borrow();
+        let first = mbcs.partition_point(|mbc| mbc.pos < line_start);
+        let mut pos = line_start;
+        let mut units = 0;
+        for mbc in mbcs[first..].iter().take_while(|mbc| mbc.pos < line_end) {
+            // Every character up to this one is a single byte and unit
+            let single_bytes = (mbc.pos - pos).to_usize();
+            if units + single_bytes >= col {
+                return pos + BytePos::from_usize(col - units);
+            }
+            units += single_bytes + if mbc.bytes == 4 { 2 } else { 1 };
+            if units > col {
+                return mbc.pos;
+            }
+            pos = mbc.pos + BytePos::from_usize(mbc.bytes);
+        }
+        cmp::min(pos + BytePos::from_usize(col - units), line_end)
+    }
+
     pub fn record_multibyte_char(&self, pos: BytePos, bytes: usize) {
         assert!(bytes >=2 && bytes <= 4);
         let mbc = MultiByteChar {
@@ -586,6 +686,43 @@ impl FileMap {
     }
 }
 
+/// The byte offsets of the start and end of the `n`th (0-based) occurrence of
+/// `substring` in `src`.
+pub fn find_substr(src: &str, substring: &str, n: usize) -> Option<(usize, usize)> {
+    let mut lo = 0;
+    let mut hi = 0;
+    for _ in 0..n + 1 {
+        lo = match src[hi..].find(substring) {
+            Some(offset) => hi + offset,
+            None => return None,
+        };
+        hi = lo + substring.len();
+    }
+    Some((lo, hi))
+}
+
+/// Finds the beginnings of lines and the multi-byte characters in `src`, for a
+/// source starting at `start_pos`.
+pub fn analyze_source(src: &str, start_pos: BytePos) -> (Vec<BytePos>, Vec<MultiByteChar>) {
+    let mut lines = vec![start_pos];
+    let mut multibyte_chars = vec![];
+    for (offset, c) in src.char_indices() {
+        let pos = start_pos + BytePos::from_usize(offset);
+        if c == '\n' {
+            // There is no line after a newline which ends the file
+            if offset + 1 < src.len() {
+                lines.push(pos + BytePos(1));
+            }
+        } else if c.len_utf8() > 1 {
+            multibyte_chars.push(MultiByteChar {
+                pos: pos,
+                bytes: c.len_utf8(),
+            });
+        }
+    }
+    (lines, multibyte_chars)
+}
+
 /// An abstraction over the fs operations used by the Parser.
 pub trait FileLoader {
     /// Query the existence of a file.
@@ -593,6 +730,13 @@ pub trait FileLoader {
 
     /// Read the contents of an UTF-8 file into memory.
     fn read_file(&self, path: &Path) -> io::Result<String>;
+
+    /// Replace the contents of a file. Loaders which can't write files
+    /// return an error.
+    fn write_file(&self, path: &Path, _contents: &str) -> io::Result<()> {
+        Err(io::Error::other(format!("can't write {}: file loader is read-only",
+                                     path.display())))
+    }
 }
 
 /// A FileLoader that uses std::fs to load real files.
@@ -608,6 +752,10 @@ impl FileLoader for RealFileLoader {
         fs::File::open(path)?.read_to_string(&mut src)?;
         Ok(src)
     }
+
+    fn write_file(&self, path: &Path, contents: &str) -> io::Result<()> {
+        fs::File::create(path)?.write_all(contents.as_bytes())
+    }
 }
 
 // _____________________________________________________________________________
@@ -617,7 +765,7 @@ impl FileLoader for RealFileLoader {
 pub struct CodeMap {
     pub files: RefCell<Vec<Rc<FileMap>>>,
     expansions: RefCell<Vec<ExpnInfo>>,
-    file_loader: Box<FileLoader>,
+    file_loader: Box<dyn FileLoader>,
 }
 
 impl CodeMap {
@@ -629,7 +777,7 @@ impl CodeMap {
         }
     }
 
-    pub fn with_file_loader(file_loader: Box<FileLoader>) -> CodeMap {
+    pub fn with_file_loader(file_loader: Box<dyn FileLoader>) -> CodeMap {
         CodeMap {
             files: RefCell::new(Vec::new()),
             expansions: RefCell::new(Vec::new()),
@@ -641,9 +789,21 @@ impl CodeMap {
         self.file_loader.file_exists(path)
     }
 
+    /// Reads a file through the file loader and adds it, with its lines and
+    /// multi-byte characters recorded.
     pub fn load_file(&self, path: &Path) -> io::Result<Rc<FileMap>> {
         let src = self.file_loader.read_file(path)?;
-        Ok(self.new_filemap(path.to_str().unwrap().to_string(), src))
+        let fm = self.new_filemap(path.to_str().unwrap().to_string(), src);
+        fm.analyze_source();
+        Ok(fm)
+    }
+
+    pub fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.file_loader.read_file(path)
+    }
+
+    pub fn write_file(&self, path: &Path, contents: &str) -> io::Result<()> {
+        self.file_loader.write_file(path, contents)
     }
 
     fn next_start_pos(&self) -> usize {
@@ -676,6 +836,7 @@ impl CodeMap {
             end_pos: Pos::from_usize(end_pos),
             lines: RefCell::new(Vec::new()),
             multibyte_chars: RefCell::new(Vec::new()),
+            multibyte_extra_bytes: RefCell::new(Vec::new()),
         });
 
         files.push(filemap.clone());
@@ -683,17 +844,11 @@ impl CodeMap {
         filemap
     }
 
-    /// Creates a new filemap and sets its line information.
+    /// Creates a new filemap and records its lines and multi-byte characters,
+    /// so that positions in it can be looked up right away.
     pub fn new_filemap_and_lines(&self, filename: &str, src: &str) -> Rc<FileMap> {
         let fm = self.new_filemap(filename.to_string(), src.to_owned());
-        let mut byte_pos: u32 = fm.start_pos.0;
-        for line in src.lines() {
-            // register the start of this line
-            fm.next_line(BytePos(byte_pos));
-
-            // update byte_pos to include this line and the \n at the end
-            byte_pos += line.len() as u32 + 1;
-        }
+        fm.analyze_source();
         fm
     }
 
@@ -729,6 +884,7 @@ impl CodeMap {
             end_pos: end_pos,
             lines: RefCell::new(file_local_lines),
             multibyte_chars: RefCell::new(file_local_multibyte_chars),
+            multibyte_extra_bytes: RefCell::new(Vec::new()),
         });
 
         files.push(filemap.clone());
@@ -799,6 +955,52 @@ impl CodeMap {
         Ok(FileMapAndLine { fm: f, line: a })
     }
 
+    /// Lookup the line and UTF-16 column of a BytePos, as the Language
+    /// Server Protocol counts them
+    pub fn lookup_utf16_pos(&self, pos: BytePos) -> Utf16Pos {
+        match self.lookup_line(pos) {
+            Ok(FileMapAndLine { fm: f, line: a }) => {
+                let linebpos = (*f.lines.borrow())[a];
+                Utf16Pos {
+                    line: a,
+                    col: f.utf16_len(linebpos, pos),
+                }
+            }
+            Err(f) => {
+                Utf16Pos {
+                    line: 0,
+                    col: f.utf16_len(f.start_pos, pos),
+                }
+            }
+        }
+    }
+
+    /// The BytePos of a line and UTF-16 column in `fm`. As editors do,
+    /// columns past the end of a line go to the end of its text, and lines
+    /// past the end of the file to the end of the file.
+    pub fn utf16_pos_to_byte_pos(&self, fm: &FileMap, pos: Utf16Pos) -> BytePos {
+        let lines = fm.lines.borrow();
+        if pos.line >= lines.len() {
+            return fm.end_pos;
+        }
+        let line_start = lines[pos.line];
+        let mut line_end = lines.get(pos.line + 1).cloned().unwrap_or(fm.end_pos);
+
+        // Leave out the line ending
+        match fm.src {
+            Some(ref src) => {
+                let text = &src[(line_start - fm.start_pos).to_usize()..
+                                (line_end - fm.start_pos).to_usize()];
+                let text = text.strip_suffix('\n').unwrap_or(text);
+                let text = text.strip_suffix('\r').unwrap_or(text);
+                line_end = line_start + BytePos::from_usize(text.len());
+            }
+            None if pos.line + 1 < lines.len() => line_end = line_end - BytePos(1),
+            None => {}
+        }
+        fm.utf16_col_to_byte_pos(line_start, line_end, pos.col)
+    }
+
     pub fn lookup_char_pos_adj(&self, pos: BytePos) -> LocWithOpt {
         let loc = self.lookup_char_pos(pos);
         LocWithOpt {
@@ -1068,6 +1270,24 @@ impl CodeMap {
         }
     }
 
+    /// Returns the span of the `n`th (0-based) occurrence of `substring` in
+    /// `file`, or `None` if there are not that many occurrences or the
+    /// source of the file is not available.
+    pub fn span_substr(&self, file: &FileMap, substring: &str, n: usize) -> Option<Span> {
+        let src = match file.src {
+            Some(ref src) => src,
+            None => return None,
+        };
+
+        find_substr(src, substring, n).map(|(lo, hi)| {
+            Span {
+                lo: file.start_pos + BytePos::from_usize(lo),
+                hi: file.start_pos + BytePos::from_usize(hi),
+                expn_id: NO_EXPANSION,
+            }
+        })
+    }
+
     pub fn get_filemap(&self, filename: &str) -> Rc<FileMap> {
         for fm in self.files.borrow().iter() {
             if filename == fm.name {
@@ -1094,21 +1314,9 @@ impl CodeMap {
         let files = self.files.borrow();
         let map = &(*files)[idx];
 
-        // The number of extra bytes due to multibyte chars in the FileMap
-        let mut total_extra_bytes = 0;
-
-        for mbc in map.multibyte_chars.borrow().iter() {
-            if mbc.pos < bpos {
-                // every character is at least one byte, so we only
-                // count the actual extra bytes.
-                total_extra_bytes += mbc.bytes - 1;
-                // We should never see a byte position in the middle of a
-                // character
-                assert!(bpos.to_usize() >= mbc.pos.to_usize() + mbc.bytes);
-            } else {
-                break;
-            }
-        }
+        // every character is at least one byte, so we only count the actual
+        // extra bytes of the multibyte chars before it
+        let total_extra_bytes = map.extra_bytes_before(bpos);
 
         assert!(map.start_pos.to_usize() + total_extra_bytes <= bpos.to_usize());
         CharPos(bpos.to_usize() - map.start_pos.to_usize() - total_extra_bytes)
@@ -1500,6 +1708,120 @@ mod tests {
         assert_eq!(lines.lines, expected);
     }
 
+    #[test]
+    fn span_substr() {
+        let cm = init_code_map();
+        let fm = cm.get_filemap("blork2.rs");
+
+        let span = cm.span_substr(&fm, "line", 1).unwrap();
+        assert_eq!(span.lo, fm.start_pos + BytePos(19));
+        assert_eq!(&cm.span_to_snippet(span).unwrap(), "line");
+
+        assert!(cm.span_substr(&fm, "line", 2).is_none());
+        assert!(cm.span_substr(&fm, "blork", 0).is_none());
+    }
+
+    #[test]
+    fn multibyte_chars_recorded_between_lookups() {
+        let cm = CodeMap::new();
+        let fm = cm.new_filemap("blork.rs".to_string(), "€€ a €€ b".to_string());
+        fm.next_line(BytePos(0));
+        fm.record_multibyte_char(BytePos(0), 3);
+        fm.record_multibyte_char(BytePos(3), 3);
+        assert_eq!(cm.bytepos_to_file_charpos(BytePos(7)), CharPos(3));
+
+        fm.record_multibyte_char(BytePos(9), 3);
+        fm.record_multibyte_char(BytePos(12), 3);
+        assert_eq!(cm.bytepos_to_file_charpos(BytePos(7)), CharPos(3));
+        assert_eq!(cm.bytepos_to_file_charpos(BytePos(16)), CharPos(8));
+
+        // Replacing them, even with as many, starts the totals over
+        let mbc = |pos: u32, bytes: usize| MultiByteChar { pos: BytePos(pos), bytes: bytes };
+        fm.set_multibyte_chars(vec![mbc(0, 2), mbc(2, 2), mbc(9, 3), mbc(12, 3)]);
+        assert_eq!(cm.bytepos_to_file_charpos(BytePos(7)), CharPos(5));
+        assert_eq!(cm.bytepos_to_file_charpos(BytePos(16)), CharPos(10));
+        fm.set_multibyte_chars(vec![mbc(0, 2), mbc(2, 2), mbc(4, 2), mbc(9, 3), mbc(12, 3)]);
+        assert_eq!(cm.bytepos_to_file_charpos(BytePos(7)), CharPos(4));
+    }
+
+    #[test]
+    fn analyze_source() {
+        let cm = CodeMap::new();
+        cm.new_filemap_and_lines("blork.rs", "first line.\n");
+        let fm = cm.new_filemap_and_lines("blork2.rs", "fn 変数() {\r\n    é\r\n}");
+
+        let lines: Vec<_> = fm.lines.borrow().iter().map(|&pos| pos - fm.start_pos).collect();
+        assert_eq!(lines, vec![BytePos(0), BytePos(15), BytePos(23)]);
+        assert_eq!(fm.multibyte_chars().len(), 3);
+        assert_eq!(fm.get_line(0), Some("fn 変数() {"));
+        assert_eq!(fm.get_line(1), Some("    é"));
+        assert_eq!(fm.get_line(2), Some("}"));
+
+        let paren = cm.span_substr(&fm, "(", 0).unwrap();
+        let loc = cm.lookup_char_pos(paren.lo);
+        assert_eq!((loc.line, loc.col), (1, CharPos(5)));
+        let brace = cm.span_substr(&fm, "}", 0).unwrap();
+        let loc = cm.lookup_char_pos(brace.lo);
+        assert_eq!((loc.line, loc.col), (3, CharPos(0)));
+    }
+
+    #[test]
+    fn utf16_positions() {
+        let cm = CodeMap::new();
+        cm.new_filemap_and_lines("blork.rs", "first line.\n");
+        let fm = cm.new_filemap_and_lines("blork2.rs", "let 𝔸 = \"é😀\";\r\n𝔹\n");
+        let pos = |s: &str| cm.span_substr(&fm, s, 0).unwrap().lo;
+
+        // 𝔸 and 😀 take four bytes and two UTF-16 units, é two bytes and one
+        let expected = [("𝔸", 0, 4), (" =", 0, 6), ("é", 0, 10), ("😀", 0, 11),
+                        ("\";", 0, 13), ("\r", 0, 15), ("𝔹", 1, 0)];
+        for &(s, line, col) in &expected {
+            let utf16_pos = Utf16Pos {
+                line: line,
+                col: col,
+            };
+            assert_eq!(cm.lookup_utf16_pos(pos(s)), utf16_pos, "{}", s);
+            assert_eq!(cm.utf16_pos_to_byte_pos(&fm, utf16_pos), pos(s), "{}", s);
+        }
+        assert_eq!(cm.lookup_utf16_pos(fm.end_pos - BytePos(1)),
+                   Utf16Pos { line: 1, col: 2 });
+
+        let at = |line: usize, col: usize| {
+            cm.utf16_pos_to_byte_pos(&fm,
+                                     Utf16Pos {
+                                         line: line,
+                                         col: col,
+                                     })
+        };
+        // The middle of a surrogate pair goes to the start of the character
+        assert_eq!(at(0, 5), pos("𝔸"));
+        assert_eq!(at(0, 12), pos("😀"));
+        // Past the end of a line, before its line ending
+        assert_eq!(at(0, 99), pos("\r"));
+        assert_eq!(at(1, 99), fm.end_pos - BytePos(1));
+        assert_eq!(at(5, 0), fm.end_pos);
+    }
+
+    #[test]
+    fn load_file_analyzes_source() {
+        struct OneFile;
+        impl FileLoader for OneFile {
+            fn file_exists(&self, _: &Path) -> bool {
+                true
+            }
+            fn read_file(&self, _: &Path) -> io::Result<String> {
+                Ok(String::from("\u{feff}a\nb → c\n"))
+            }
+        }
+
+        let cm = CodeMap::with_file_loader(Box::new(OneFile));
+        let fm = cm.load_file(Path::new("blork.rs")).unwrap();
+        assert_eq!(fm.get_line(1), Some("b → c"));
+        let c = cm.span_substr(&fm, "c", 0).unwrap();
+        let loc = cm.lookup_char_pos(c.lo);
+        assert_eq!((loc.line, loc.col), (2, CharPos(4)));
+    }
+
     #[test]
     fn t8() {
         // Test span_to_snippet for a span ending at the end of filemap
@@ -1,23 +1,80 @@
-use std::fmt;
 use std::rc::Rc;
 
-use term;
-
 use styled_buffer::*;
-use codemap::{self, Span, CharPos, FileMap, SpanLabel};
+use codemap::{self, MultiSpan, Span, SpanLabel};
 
-pub struct CompilerMessage {
+/// A message with the spans it points at. `cm` is the map the spans are
+/// rendered against, usually a `CodeMap`; messages built where there is none
+/// yet leave it out, like `sync::PendingMessage`.
+#[derive(Clone, Debug)]
+pub struct CompilerMessage<M = Rc<codemap::CodeMap>> {
     pub level: Level,
     pub primary_span: Span,
     pub primary_msg: String,
     pub span_labels: Vec<SpanLabel>,
-    pub notes: Vec<String>,
+    pub children: Vec<SubDiagnostic>,
+    pub suggestions: Vec<CodeSuggestion>,
     pub error_code: Option<String>,
-    pub cm: Rc<codemap::CodeMap>,
+    /// Keeps the labels pointing into macro expansions where they are, even
+    /// when the renderer would move them to the macro invocations.
+    pub keep_expansion_spans: bool,
+    pub cm: M,
 }
 
-impl CompilerMessage {
-    pub fn span_label(&mut self, span: Span, label: Option<String>) -> &mut CompilerMessage {
+/// A note, help or warning attached to a message, optionally pointing at
+/// source of its own.
+#[derive(Clone, Debug)]
+pub struct SubDiagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Option<MultiSpan>,
+}
+
+/// A suggested change to the source, made of one or more replacements which
+/// are meant to be applied together.
+#[derive(Clone, Debug)]
+pub struct CodeSuggestion {
+    pub msg: String,
+    pub parts: Vec<SubstitutionPart>,
+    pub applicability: Applicability,
+}
+
+#[derive(Clone, Debug)]
+pub struct SubstitutionPart {
+    pub span: Span,
+    pub snippet: String,
+}
+
+/// How confident we are that a suggestion is what the user wants.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Applicability {
+    /// The suggestion is definitely what the user intended, and can be
+    /// applied automatically.
+    MachineApplicable,
+    /// The suggestion may be what the user intended, but it is uncertain.
+    /// It should still compile if applied.
+    MaybeIncorrect,
+    /// The suggestion contains placeholders like `(...)` which the user
+    /// has to fill in, so it won't compile as is.
+    HasPlaceholders,
+    /// The applicability of the suggestion is unknown.
+    Unspecified,
+}
+
+impl Applicability {
+    /// The name used for this applicability in rustc's JSON output.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Applicability::MachineApplicable => "MachineApplicable",
+            Applicability::MaybeIncorrect => "MaybeIncorrect",
+            Applicability::HasPlaceholders => "HasPlaceholders",
+            Applicability::Unspecified => "Unspecified",
+        }
+    }
+}
+
+impl<M> CompilerMessage<M> {
+    pub fn span_label(&mut self, span: Span, label: Option<String>) -> &mut CompilerMessage<M> {
         self.span_labels.push(SpanLabel {
             span: span,
             is_primary: (span == self.primary_span),
@@ -26,11 +83,88 @@ impl CompilerMessage {
         self
     }
 
-    pub fn note(&mut self, note: String) -> &mut CompilerMessage {
-        self.notes.push(note);
+    pub fn note(&mut self, note: String) -> &mut CompilerMessage<M> {
+        self.sub(Level::Note, note, None)
+    }
+
+    pub fn span_note<S: Into<MultiSpan>>(&mut self,
+                                         span: S,
+                                         note: String)
+                                         -> &mut CompilerMessage<M> {
+        self.sub(Level::Note, note, Some(span.into()))
+    }
+
+    pub fn help(&mut self, help: String) -> &mut CompilerMessage<M> {
+        self.sub(Level::Help, help, None)
+    }
+
+    pub fn span_help<S: Into<MultiSpan>>(&mut self,
+                                         span: S,
+                                         help: String)
+                                         -> &mut CompilerMessage<M> {
+        self.sub(Level::Help, help, Some(span.into()))
+    }
+
+    pub fn warn(&mut self, warning: String) -> &mut CompilerMessage<M> {
+        self.sub(Level::Warning, warning, None)
+    }
+
+    pub fn span_warn<S: Into<MultiSpan>>(&mut self,
+                                         span: S,
+                                         warning: String)
+                                         -> &mut CompilerMessage<M> {
+        self.sub(Level::Warning, warning, Some(span.into()))
+    }
+
+    /// Adds a child message. Children with a span are rendered with their own
+    /// annotated snippet beneath the parent, the others as a single line.
+    pub fn sub(&mut self,
+               level: Level,
+               message: String,
+               span: Option<MultiSpan>)
+               -> &mut CompilerMessage<M> {
+        self.children.push(SubDiagnostic {
+            level: level,
+            message: message,
+            span: span,
+        });
+        self
+    }
+
+    /// Suggests replacing `span` with `suggestion`.
+    pub fn span_suggestion(&mut self,
+                           span: Span,
+                           msg: String,
+                           suggestion: String,
+                           applicability: Applicability)
+                           -> &mut CompilerMessage<M> {
+        self.multipart_suggestion(msg, vec![(span, suggestion)], applicability)
+    }
+
+    /// Suggests a change made of several replacements, which are shown and
+    /// applied together.
+    pub fn multipart_suggestion(&mut self,
+                                msg: String,
+                                parts: Vec<(Span, String)>,
+                                applicability: Applicability)
+                                -> &mut CompilerMessage<M> {
+        self.suggestions.push(CodeSuggestion {
+            msg: msg,
+            parts: parts.into_iter()
+                .map(|(span, snippet)| {
+                    SubstitutionPart {
+                        span: span,
+                        snippet: snippet,
+                    }
+                })
+                .collect(),
+            applicability: applicability,
+        });
         self
     }
+}
 
+impl CompilerMessage {
     pub fn new(level: Level,
                msg: String,
                primary_span: Span,
@@ -44,7 +178,9 @@ impl CompilerMessage {
             primary_msg: msg,
             error_code: error_code,
             span_labels: vec![],
-            notes: vec![],
+            children: vec![],
+            suggestions: vec![],
+            keep_expansion_spans: false,
             cm: cm,
         }
     }
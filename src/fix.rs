@@ -0,0 +1,335 @@
+//! Applies the machine-applicable suggestions of a set of diagnostics to the
+//! source files they point into, in the same way `rustfix` does for rustc.
+//!
+//! Each suggestion is resolved back to its `FileMap` and turned into byte
+//! edits. A suggestion is applied as a whole or not at all: if any of its
+//! parts can't be resolved, or overlaps an edit that was already accepted,
+//! the suggestion is skipped and reported back to the caller.
+
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use compiler_message::*;
+use codemap::{CodeMap, FileMap, FileName, Pos};
+
+/// A single replacement in a file, in byte offsets relative to the start of
+/// the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replacement {
+    pub lo: usize,
+    pub hi: usize,
+    pub snippet: String,
+}
+
+impl Replacement {
+    fn conflicts_with(&self, other: &Replacement) -> bool {
+        if self.lo == self.hi && other.lo == other.hi {
+            // Two insertions at the same point have no natural order
+            self.lo == other.lo
+        } else {
+            self.lo < other.hi && other.lo < self.hi
+        }
+    }
+}
+
+/// Why a suggestion was not applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// One of the parts doesn't point into a file whose source we have.
+    Unresolvable,
+    /// One of the parts overlaps an edit from an earlier suggestion, or
+    /// another part of the same suggestion.
+    Overlapping,
+}
+
+/// The contents of a file after the suggestions were applied to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FixedFile {
+    pub name: FileName,
+    pub src: String,
+    /// The edits which were applied, sorted by position.
+    pub replacements: Vec<Replacement>,
+}
+
+#[derive(Debug)]
+pub struct FixResult {
+    /// The files with at least one edit, in the order they were first edited.
+    /// A file added to the codemap more than once under the same name is
+    /// edited through the `FileMap` the spans point into.
+    pub files: Vec<FixedFile>,
+    pub skipped: Vec<(CodeSuggestion, SkipReason)>,
+}
+
+impl FixResult {
+    /// Writes the updated files back through the file loader of `cm`.
+    pub fn write(&self, cm: &CodeMap) -> io::Result<()> {
+        for file in &self.files {
+            cm.write_file(Path::new(&file.name), &file.src)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the machine-applicable suggestions of `msgs` and applies the ones
+/// which don't conflict with each other, in order. Suggestions which are
+/// emitted more than once are only applied once.
+pub fn apply_suggestions(cm: &CodeMap, msgs: &[CompilerMessage]) -> FixResult {
+    let mut edits: Vec<(Rc<FileMap>, Vec<Replacement>)> = vec![];
+    let mut skipped = vec![];
+
+    let suggestions = msgs.iter()
+        .flat_map(|msg| &msg.suggestions)
+        .filter(|s| s.applicability == Applicability::MachineApplicable);
+    for suggestion in suggestions {
+        let parts = match resolve_parts(cm, suggestion) {
+            Some(parts) => parts,
+            None => {
+                skipped.push((suggestion.clone(), SkipReason::Unresolvable));
+                continue;
+            }
+        };
+
+        // The same fix reported twice is only applied once
+        let already_applied = parts.iter().all(|(fm, replacement)| {
+            edits.iter()
+                .any(|(f, reps)| Rc::ptr_eq(f, fm) && reps.contains(replacement))
+        });
+        if already_applied {
+            continue;
+        }
+
+        let overlapping = parts.iter().enumerate().any(|(i, (fm, replacement))| {
+            let against_parts = parts[..i]
+                .iter()
+                .any(|(f, r)| Rc::ptr_eq(f, fm) && r.conflicts_with(replacement));
+            let against_edits = edits.iter()
+                .filter(|&(f, _)| Rc::ptr_eq(f, fm))
+                .any(|(_, reps)| reps.iter().any(|r| r.conflicts_with(replacement)));
+            against_parts || against_edits
+        });
+        if overlapping {
+            skipped.push((suggestion.clone(), SkipReason::Overlapping));
+            continue;
+        }
+
+        for (fm, replacement) in parts {
+            match edits.iter().position(|(f, _)| Rc::ptr_eq(f, &fm)) {
+                Some(idx) => edits[idx].1.push(replacement),
+                None => edits.push((fm, vec![replacement])),
+            }
+        }
+    }
+
+    let files = edits.into_iter()
+        .map(|(fm, mut replacements)| {
+            let src = fm.src.as_ref().expect("edited files have their source");
+            replacements.sort_by_key(|r| (r.lo, r.hi));
+            FixedFile {
+                name: fm.name.clone(),
+                src: apply_replacements(src, &replacements),
+                replacements: replacements,
+            }
+        })
+        .collect();
+
+    FixResult {
+        files: files,
+        skipped: skipped,
+    }
+}
+
+/// Resolves each part of the suggestion to a file and file-relative offsets.
+fn resolve_parts(cm: &CodeMap,
+                 suggestion: &CodeSuggestion)
+                 -> Option<Vec<(Rc<FileMap>, Replacement)>> {
+    suggestion.parts
+        .iter()
+        .map(|part| {
+            // `span_to_snippet` checks the span lies within a single file
+            // whose source is available
+            if cm.span_to_snippet(part.span).is_err() {
+                return None;
+            }
+            let lo = cm.lookup_byte_offset(part.span.lo);
+            let hi = cm.lookup_byte_offset(part.span.hi);
+            Some((lo.fm,
+                  Replacement {
+                      lo: lo.pos.to_usize(),
+                      hi: hi.pos.to_usize(),
+                      snippet: part.snippet.clone(),
+                  }))
+        })
+        .collect()
+}
+
+/// Splices sorted, non-overlapping replacements into `src`.
+pub fn apply_replacements(src: &str, replacements: &[Replacement]) -> String {
+    let mut output = String::with_capacity(src.len());
+    let mut cursor = 0;
+    for replacement in replacements {
+        output.push_str(&src[cursor..replacement.lo]);
+        output.push_str(&replacement.snippet);
+        cursor = replacement.hi;
+    }
+    output.push_str(&src[cursor..]);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use codemap::Span;
+    use styled_buffer::Level;
+    use test_utils::MemLoader;
+
+    fn setup(files: &[(&str, &str)]) -> (MemLoader, Rc<CodeMap>) {
+        let loader = MemLoader::default();
+        for &(name, src) in files {
+            loader.insert(name, src);
+        }
+        let cm = Rc::new(CodeMap::with_file_loader(Box::new(loader.clone())));
+        for &(name, _) in files {
+            cm.load_file(Path::new(name)).unwrap();
+        }
+        (loader, cm)
+    }
+
+    fn span(cm: &CodeMap, file: &str, substring: &str) -> Span {
+        cm.span_substr(&cm.get_filemap(file), substring, 0).unwrap()
+    }
+
+    fn message(cm: &Rc<CodeMap>, sp: Span) -> CompilerMessage {
+        CompilerMessage::new(Level::Error, String::from("error"), sp, None, cm.clone())
+    }
+
+    #[test]
+    fn applies_and_writes_back() {
+        let (loader, cm) = setup(&[("a.rs", "let x = 1;\nx = 2;\n"),
+                                   ("b.rs", "fn foo() {}\n")]);
+
+        let mut msg1 = message(&cm, span(&cm, "a.rs", "x = 2"));
+        msg1.span_suggestion(Span { hi: span(&cm, "a.rs", "x").lo, ..span(&cm, "a.rs", "x") },
+                             String::from("make this binding mutable"),
+                             String::from("mut "),
+                             Applicability::MachineApplicable);
+        let mut msg2 = message(&cm, span(&cm, "b.rs", "foo"));
+        msg2.span_suggestion(span(&cm, "b.rs", "foo"),
+                             String::from("use snake case"),
+                             String::from("foo_bar"),
+                             Applicability::MachineApplicable);
+        msg2.span_suggestion(span(&cm, "b.rs", "{}"),
+                             String::from("not sure about this one"),
+                             String::from("{ unimplemented!() }"),
+                             Applicability::MaybeIncorrect);
+
+        let result = apply_suggestions(&cm, &[msg1, msg2]);
+        assert!(result.skipped.is_empty());
+        assert_eq!(result.files.len(), 2);
+        assert_eq!(result.files[0].src, "let mut x = 1;\nx = 2;\n");
+        assert_eq!(result.files[1].src, "fn foo_bar() {}\n");
+
+        result.write(&cm).unwrap();
+        assert_eq!(loader.contents("a.rs"), "let mut x = 1;\nx = 2;\n");
+        assert_eq!(loader.contents("b.rs"), "fn foo_bar() {}\n");
+    }
+
+    #[test]
+    fn skips_overlapping_suggestions() {
+        let (_, cm) = setup(&[("a.rs", "foo(bar);\n")]);
+
+        let mut msg = message(&cm, span(&cm, "a.rs", "foo(bar)"));
+        msg.span_suggestion(span(&cm, "a.rs", "foo(bar)"),
+                            String::from("call baz instead"),
+                            String::from("baz()"),
+                            Applicability::MachineApplicable);
+        msg.multipart_suggestion(String::from("borrow the argument"),
+                                 vec![(span(&cm, "a.rs", "foo"), String::from("foo")),
+                                      (span(&cm, "a.rs", "bar"), String::from("&bar"))],
+                                 Applicability::MachineApplicable);
+        msg.span_suggestion(span(&cm, "a.rs", ";"),
+                            String::from("remove the semicolon"),
+                            String::new(),
+                            Applicability::MachineApplicable);
+
+        let result = apply_suggestions(&cm, &[msg]);
+        assert_eq!(result.files.len(), 1);
+        assert_eq!(result.files[0].src, "baz()\n");
+        assert_eq!(result.files[0].replacements.len(), 2);
+        assert_eq!(result.skipped.len(), 1);
+        assert_eq!(result.skipped[0].0.msg, "borrow the argument");
+        assert_eq!(result.skipped[0].1, SkipReason::Overlapping);
+    }
+
+    #[test]
+    fn duplicate_suggestions_apply_once() {
+        let (_, cm) = setup(&[("a.rs", "let x = 1;\n")]);
+
+        let msgs: Vec<_> = (0..2)
+            .map(|_| {
+                let mut msg = message(&cm, span(&cm, "a.rs", "1"));
+                msg.span_suggestion(span(&cm, "a.rs", "1"),
+                                    String::from("use a float"),
+                                    String::from("1.0"),
+                                    Applicability::MachineApplicable);
+                msg
+            })
+            .collect();
+
+        let result = apply_suggestions(&cm, &msgs);
+        assert!(result.skipped.is_empty());
+        assert_eq!(result.files[0].src, "let x = 1.0;\n");
+    }
+
+    #[test]
+    fn skips_unresolvable_suggestions() {
+        let (_, cm) = setup(&[("a.rs", "let x = 1;\n")]);
+        cm.new_imported_filemap(String::from("b.rs"), 10, vec![], vec![]);
+        let imported = cm.get_filemap("b.rs");
+
+        let mut msg = message(&cm, span(&cm, "a.rs", "x"));
+        let whole_file = Span {
+            lo: imported.start_pos,
+            hi: imported.end_pos,
+            ..span(&cm, "a.rs", "x")
+        };
+        msg.span_suggestion(whole_file,
+                            String::from("replace the whole file"),
+                            String::new(),
+                            Applicability::MachineApplicable);
+
+        let result = apply_suggestions(&cm, &[msg]);
+        assert!(result.files.is_empty());
+        assert_eq!(result.skipped[0].1, SkipReason::Unresolvable);
+    }
+
+    #[test]
+    fn edits_the_file_the_spans_point_into() {
+        let (_, cm) = setup(&[("a.rs", "let x = 1;\n")]);
+        let reloaded = cm.new_filemap_and_lines("a.rs", "let mut x = 1;\nx = 2;\n");
+        let stale = cm.get_filemap("a.rs");
+
+        let two = cm.span_substr(&reloaded, "2", 0).unwrap();
+        let mut msg = message(&cm, two);
+        msg.span_suggestion(two,
+                            String::from("use a float"),
+                            String::from("2.0"),
+                            Applicability::MachineApplicable);
+        let one = cm.span_substr(&stale, "1", 0).unwrap();
+        msg.span_suggestion(one,
+                            String::from("use a float"),
+                            String::from("1.0"),
+                            Applicability::MachineApplicable);
+
+        // Each edit goes into the file its span points into, rather than the
+        // first one with its name
+        let result = apply_suggestions(&cm, &[msg]);
+        assert!(result.skipped.is_empty());
+        assert_eq!(result.files.len(), 2);
+        assert_eq!(result.files[0].src, "let mut x = 1;\nx = 2.0;\n");
+        assert_eq!(result.files[1].src, "let x = 1.0;\n");
+    }
+}
@@ -0,0 +1,440 @@
+//! The `Handler` is the central place diagnostics are sent to. It passes them
+//! on to an `Emitter`, keeps count of them by level, and decides when the
+//! compilation has to stop.
+//!
+//! Stopping follows rustc: a fatal error unwinds with a `FatalError` payload,
+//! which the driver is expected to catch at the top, with
+//! `panic::catch_unwind`, and turn into a failing exit status. Bugs, and errors
+//! with `treat_err_as_bug` set, are real panics.
+//!
+//! A handler can be given a `Registry` of error code explanations. Messages
+//! with a code which isn't registered are then bugs, and the error count is
+//! followed by a pointer to the explanations of the codes of the errors.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::panic;
+use std::rc::Rc;
+
+use styled_buffer::Level;
+use styled_emit::Emitter;
+use compiler_message::CompilerMessage;
+use codemap::{CodeMap, DUMMY_SP};
+use registry::Registry;
+
+/// Used as the payload of the unwinding started by a fatal error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FatalError;
+
+impl FatalError {
+    pub fn raise(self) -> ! {
+        panic::resume_unwind(Box::new(self))
+    }
+}
+
+impl fmt::Display for FatalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compilation aborted due to a fatal error")
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HandlerFlags {
+    /// Panic on the first error, to get a backtrace of where it was reported.
+    pub treat_err_as_bug: bool,
+    /// Abort once this many errors have been reported.
+    pub error_limit: Option<usize>,
+}
+
+pub struct Handler {
+    pub flags: HandlerFlags,
+    emitter: RefCell<Box<dyn Emitter>>,
+    cm: Rc<CodeMap>,
+    counts: RefCell<HashMap<Level, usize>>,
+    aborted: Cell<bool>,
+    registry: Option<Registry>,
+    /// The codes of the errors emitted so far which have an explanation.
+    explained_codes: RefCell<BTreeSet<String>>,
+}
+
+impl Handler {
+    pub fn new(emitter: Box<dyn Emitter>, cm: Rc<CodeMap>) -> Handler {
+        Handler::with_flags(emitter, cm, HandlerFlags::default())
+    }
+
+    pub fn with_flags(emitter: Box<dyn Emitter>, cm: Rc<CodeMap>, flags: HandlerFlags) -> Handler {
+        Handler {
+            flags: flags,
+            emitter: RefCell::new(emitter),
+            cm: cm,
+            counts: RefCell::new(HashMap::new()),
+            aborted: Cell::new(false),
+            registry: None,
+            explained_codes: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    /// Checks the codes of the messages emitted from now on against
+    /// `registry`, and points to their explanations after the error count.
+    pub fn set_registry(&mut self, registry: Registry) {
+        self.registry = Some(registry);
+    }
+
+    /// Counts and emits a message, then acts on its level:
+    ///
+    /// - `Bug` panics, as an internal compiler error.
+    /// - `Fatal` prints the error count and raises a `FatalError`.
+    /// - `PhaseFatal` and `Error` are counted as errors, which stop the
+    ///   compilation at the next `abort_if_errors`, or right away when the
+    ///   error limit is reached or errors are treated as bugs.
+    pub fn emit(&self, msg: &CompilerMessage) -> io::Result<()> {
+        if msg.level == Level::Cancelled || self.aborted.get() {
+            return Ok(());
+        }
+
+        if let Some(ref registry) = self.registry {
+            if let Err(err) = registry.validate(msg) {
+                panic!("internal compiler error: {}", err);
+            }
+            if let Some(ref code) = msg.error_code {
+                if is_error(msg.level) {
+                    self.explained_codes.borrow_mut().insert(code.clone());
+                }
+            }
+        }
+
+        *self.counts.borrow_mut().entry(msg.level).or_insert(0) += 1;
+        // The compilation stops all the same when the message can't be
+        // written, so the error is only returned when it goes on
+        let written = self.emitter.borrow_mut().emit(msg);
+
+        match msg.level {
+            Level::Bug => panic!("internal compiler error: {}", msg.primary_msg),
+            Level::Fatal => self.abort(),
+            Level::PhaseFatal | Level::Error => {
+                if self.flags.treat_err_as_bug {
+                    panic!("encountered error with `treat_err_as_bug` set");
+                }
+                match self.flags.error_limit {
+                    Some(limit) if self.err_count() >= limit => self.abort(),
+                    _ => written,
+                }
+            }
+            _ => written,
+        }
+    }
+
+    /// The number of messages emitted at `level`.
+    pub fn count(&self, level: Level) -> usize {
+        self.counts.borrow().get(&level).cloned().unwrap_or(0)
+    }
+
+    pub fn err_count(&self) -> usize {
+        self.count(Level::Bug) + self.count(Level::Fatal) + self.count(Level::PhaseFatal) +
+        self.count(Level::Error)
+    }
+
+    pub fn warn_count(&self) -> usize {
+        self.count(Level::Warning)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.err_count() > 0
+    }
+
+    /// Stops the compilation if any errors were reported, after printing how
+    /// many there were.
+    pub fn abort_if_errors(&self) -> io::Result<()> {
+        if self.has_errors() {
+            self.abort()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Prints a summary of the errors and warnings emitted so far, eg)
+    /// `error: aborting due to 2 previous errors; 1 warning emitted`, followed
+    /// by where to find the explanations of their codes.
+    pub fn print_error_count(&self) -> io::Result<()> {
+        let errors = self.err_count();
+        let warnings = match self.warn_count() {
+            0 => None,
+            1 => Some(String::from("1 warning emitted")),
+            n => Some(format!("{} warnings emitted", n)),
+        };
+
+        let (level, summary) = match (errors, warnings) {
+            (0, None) => return Ok(()),
+            (0, Some(warnings)) => (Level::Warning, warnings),
+            (errors, warnings) => {
+                let aborting = match errors {
+                    1 => String::from("aborting due to previous error"),
+                    n => format!("aborting due to {} previous errors", n),
+                };
+                match warnings {
+                    Some(warnings) => (Level::Error, format!("{}; {}", aborting, warnings)),
+                    None => (Level::Error, aborting),
+                }
+            }
+        };
+
+        let msg = CompilerMessage::new(level, summary, DUMMY_SP, None, self.cm.clone());
+        self.emitter.borrow_mut().emit(&msg)?;
+
+        if errors == 0 {
+            return Ok(());
+        }
+        let codes = self.explained_codes.borrow();
+        let mut notes = vec![];
+        match codes.len() {
+            0 => {}
+            1 => {
+                notes.push(format!("For more information about this error, try `--explain {}`.",
+                                   codes.iter().next().unwrap()));
+            }
+            _ => {
+                let codes: Vec<&str> = codes.iter().map(|c| &c[..]).collect();
+                notes.push(format!("Some errors have detailed explanations: {}.",
+                                   codes.join(", ")));
+                notes.push(format!("For more information about an error, try `--explain {}`.",
+                                   codes[0]));
+            }
+        }
+        for note in notes {
+            let msg = CompilerMessage::new(Level::FailureNote,
+                                           note,
+                                           DUMMY_SP,
+                                           None,
+                                           self.cm.clone());
+            self.emitter.borrow_mut().emit(&msg)?;
+        }
+        Ok(())
+    }
+
+    fn abort(&self) -> ! {
+        // Whatever gets reported while unwinding is only noise
+        self.aborted.set(true);
+        // Not being able to print the count is no reason to go on
+        let _ = self.print_error_count();
+        FatalError.raise()
+    }
+}
+
+fn is_error(level: Level) -> bool {
+    matches!(level, Level::Bug | Level::Fatal | Level::PhaseFatal | Level::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic::AssertUnwindSafe;
+
+    use codemap::Span;
+    use styled_emit::CaptureEmitter;
+
+    /// An emitter which only records the messages it gets, so tests can look
+    /// at them after the handler is gone.
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Emitter for Recorder {
+        fn emit(&mut self, msg: &CompilerMessage) -> io::Result<()> {
+            let mut capture = CaptureEmitter::new();
+            capture.emit(msg)?;
+            self.0.borrow_mut().push(capture.text());
+            Ok(())
+        }
+    }
+
+    /// An emitter whose output is gone.
+    struct Closed;
+
+    impl Emitter for Closed {
+        fn emit(&mut self, _: &CompilerMessage) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+    }
+
+    fn setup(flags: HandlerFlags) -> (Rc<RefCell<Vec<String>>>, Handler, Span) {
+        let cm = Rc::new(CodeMap::new());
+        let foo = cm.new_filemap_and_lines("foo.rs", "fn foo() {}\n");
+        let span = cm.span_substr(&foo, "foo", 0).unwrap();
+        let out = Rc::new(RefCell::new(vec![]));
+        let handler = Handler::with_flags(Box::new(Recorder(out.clone())), cm, flags);
+        (out, handler, span)
+    }
+
+    fn message(handler: &Handler, level: Level, span: Span) -> CompilerMessage {
+        CompilerMessage::new(level, String::from("oops"), span, None, handler.cm.clone())
+    }
+
+    fn is_fatal<F: FnOnce()>(f: F) -> bool {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(()) => false,
+            Err(payload) => payload.downcast_ref::<FatalError>().is_some(),
+        }
+    }
+
+    #[test]
+    fn counts_by_level() {
+        let (out, handler, span) = setup(HandlerFlags::default());
+        handler.emit(&message(&handler, Level::Warning, span)).unwrap();
+        handler.emit(&message(&handler, Level::Note, span)).unwrap();
+        handler.emit(&message(&handler, Level::Cancelled, span)).unwrap();
+        assert!(!handler.has_errors());
+        handler.abort_if_errors().unwrap();
+
+        handler.emit(&message(&handler, Level::Error, span)).unwrap();
+        handler.emit(&message(&handler, Level::PhaseFatal, span)).unwrap();
+        assert_eq!(handler.count(Level::Error), 1);
+        assert_eq!(handler.count(Level::Note), 1);
+        assert_eq!(handler.count(Level::Cancelled), 0);
+        assert_eq!(handler.err_count(), 2);
+        assert_eq!(handler.warn_count(), 1);
+        assert_eq!(out.borrow().len(), 4);
+
+        assert!(is_fatal(|| {
+            let _ = handler.abort_if_errors();
+        }));
+        assert_eq!(out.borrow().last().unwrap(),
+                   "error: aborting due to 2 previous errors; 1 warning emitted\n");
+    }
+
+    #[test]
+    fn error_count_summary() {
+        let (out, handler, span) = setup(HandlerFlags::default());
+        handler.print_error_count().unwrap();
+        assert!(out.borrow().is_empty());
+
+        handler.emit(&message(&handler, Level::Warning, span)).unwrap();
+        handler.emit(&message(&handler, Level::Warning, span)).unwrap();
+        handler.print_error_count().unwrap();
+        assert_eq!(out.borrow().last().unwrap(), "warning: 2 warnings emitted\n");
+
+        handler.emit(&message(&handler, Level::Error, span)).unwrap();
+        handler.print_error_count().unwrap();
+        assert_eq!(out.borrow().last().unwrap(),
+                   "error: aborting due to previous error; 2 warnings emitted\n");
+    }
+
+    #[test]
+    fn fatal_aborts() {
+        let (out, handler, span) = setup(HandlerFlags::default());
+        assert!(is_fatal(|| {
+            let _ = handler.emit(&message(&handler, Level::Fatal, span));
+        }));
+        assert_eq!(out.borrow().last().unwrap(), "error: aborting due to previous error\n");
+
+        // Nothing more is reported once aborted
+        handler.emit(&message(&handler, Level::Error, span)).unwrap();
+        assert_eq!(handler.err_count(), 1);
+    }
+
+    #[test]
+    fn error_limit() {
+        let flags = HandlerFlags { error_limit: Some(2), ..HandlerFlags::default() };
+        let (out, handler, span) = setup(flags);
+        handler.emit(&message(&handler, Level::Error, span)).unwrap();
+        assert!(is_fatal(|| {
+            let _ = handler.emit(&message(&handler, Level::Error, span));
+        }));
+        assert_eq!(out.borrow().len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "treat_err_as_bug")]
+    fn treat_err_as_bug() {
+        let flags = HandlerFlags { treat_err_as_bug: true, ..HandlerFlags::default() };
+        let (_, handler, span) = setup(flags);
+        handler.emit(&message(&handler, Level::Warning, span)).unwrap();
+        handler.emit(&message(&handler, Level::Error, span)).unwrap();
+    }
+
+    #[test]
+    fn explain_footer() {
+        let (out, mut handler, span) = setup(HandlerFlags::default());
+        handler.set_registry(Registry::from_static(&[("E0308", "Mismatched types."),
+                                                     ("E0425", "Unresolved name.")]));
+        let coded = |level: Level, code: &str| {
+            CompilerMessage::new(level,
+                                 String::from("oops"),
+                                 span,
+                                 Some(String::from(code)),
+                                 handler.cm.clone())
+        };
+
+        // Warnings don't need explaining
+        handler.emit(&coded(Level::Warning, "E0425")).unwrap();
+        handler.emit(&coded(Level::Error, "E0308")).unwrap();
+        handler.print_error_count().unwrap();
+        assert_eq!(out.borrow()[2..].join(""),
+                   "error: aborting due to previous error; 1 warning emitted\n\
+                    For more information about this error, try `--explain E0308`.\n");
+
+        handler.emit(&coded(Level::Error, "E0425")).unwrap();
+        handler.emit(&coded(Level::Error, "E0308")).unwrap();
+        handler.print_error_count().unwrap();
+        assert_eq!(out.borrow()[6..].join(""),
+                   "error: aborting due to 3 previous errors; 1 warning emitted\n\
+                    Some errors have detailed explanations: E0308, E0425.\n\
+                    For more information about an error, try `--explain E0308`.\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "error code `E9999` is not registered")]
+    fn unregistered_code_is_a_bug() {
+        let (_, mut handler, span) = setup(HandlerFlags::default());
+        handler.set_registry(Registry::new());
+        let msg = CompilerMessage::new(Level::Error,
+                                       String::from("oops"),
+                                       span,
+                                       Some(String::from("E9999")),
+                                       handler.cm.clone());
+        handler.emit(&msg).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "internal compiler error: oops")]
+    fn bug_panics() {
+        let (_, handler, span) = setup(HandlerFlags::default());
+        handler.emit(&message(&handler, Level::Bug, span)).unwrap();
+    }
+
+    #[test]
+    fn stops_when_writing_fails() {
+        let cm = Rc::new(CodeMap::new());
+        let closed = |flags: HandlerFlags| Handler::with_flags(Box::new(Closed), cm.clone(), flags);
+        let handler = closed(HandlerFlags::default());
+        assert!(handler.emit(&message(&handler, Level::Warning, DUMMY_SP)).is_err());
+        assert!(handler.emit(&message(&handler, Level::Error, DUMMY_SP)).is_err());
+        assert!(is_fatal(|| {
+            let _ = handler.abort_if_errors();
+        }));
+        // The abort still holds
+        handler.emit(&message(&handler, Level::Error, DUMMY_SP)).unwrap();
+        assert_eq!(handler.err_count(), 1);
+
+        let handler = closed(HandlerFlags::default());
+        assert!(is_fatal(|| {
+            let _ = handler.emit(&message(&handler, Level::Fatal, DUMMY_SP));
+        }));
+
+        let handler = closed(HandlerFlags { error_limit: Some(1), ..HandlerFlags::default() });
+        assert!(is_fatal(|| {
+            let _ = handler.emit(&message(&handler, Level::Error, DUMMY_SP));
+        }));
+
+        // Bugs are real panics, rather than aborts
+        let as_bug = HandlerFlags { treat_err_as_bug: true, ..HandlerFlags::default() };
+        for &(flags, level) in &[(HandlerFlags::default(), Level::Bug), (as_bug, Level::Error)] {
+            let handler = closed(flags);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _ = handler.emit(&message(&handler, level, DUMMY_SP));
+            }));
+            let payload = result.unwrap_err();
+            assert!(payload.downcast_ref::<FatalError>().is_none());
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Renders messages as HTML, for build reports published as web pages.
+//!
+//! Each styled string becomes a `<span>` with a class named after its
+//! `Style`, eg) `line-number` or `label-secondary`, and each message a
+//! `<div>` with the class of its level, so that one stylesheet colors the
+//! primary underlines and labels by level, as the terminal does.
+
+use std::fmt::Write;
+
+use styled_buffer::*;
+use compiler_message::CompilerMessage;
+use render_succinct::render_succinct;
+
+/// The stylesheet of `render_html_page`, which can be used with the other
+/// functions as well. The colors follow those of the terminal.
+pub const STYLESHEET: &str = "\
+.diagnostic { margin: 0 0 1em; }
+.diagnostic pre { margin: 0; font-family: monospace; white-space: pre; }
+.header-msg, .old-school-note-text, .level { font-weight: bold; }
+.line-number, .underline-secondary, .label-secondary { font-weight: bold; color: #2a6fdb; }
+.error-code { font-weight: bold; color: #b03bb0; }
+.addition, .old-school-note { font-weight: bold; color: #2e9e2e; }
+.underline-primary, .label-primary { font-weight: bold; }
+.level-error, .error .underline-primary, .error .label-primary { color: #d62828; }
+.level-warning, .warning .underline-primary, .warning .label-primary { color: #b58900; }
+.note .underline-primary, .note .label-primary { color: #2e9e2e; }
+.help .underline-primary, .help .label-primary { color: #1a9fa8; }
+table.summary { border-collapse: collapse; margin: 0 0 1em; }
+table.summary th, table.summary td { border: 1px solid #ccc; padding: 0.2em 0.6em; }
+";
+
+/// The classes of the spans with `style`, or None for unstyled text.
+fn style_class(style: Style) -> Option<String> {
+    match style {
+        Style::Level(level) => Some(format!("level level-{}", level.name())),
+        Style::NoStyle => None,
+        style => Some(style.name().to_owned()),
+    }
+}
+
+/// Escapes the characters which would otherwise be taken for markup.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders styled lines as the contents of a `<pre>`, a line each.
+pub fn render_html(lines: &[Vec<StyledString>]) -> String {
+    let mut html = String::new();
+    for line in lines {
+        for part in line {
+            match style_class(part.style) {
+                Some(class) => {
+                    write!(html, "<span class=\"{}\">{}</span>", class, escape_html(&part.text))
+                        .unwrap()
+                }
+                None => html.push_str(&escape_html(&part.text)),
+            }
+        }
+        html.push('\n');
+    }
+    html
+}
+
+/// Renders the message as a `<div>` with the class of its level and the
+/// anchor `id`, so reports can link to it.
+pub fn render_message_html(msg: &CompilerMessage, id: &str) -> String {
+    format!("<div class=\"diagnostic {}\" id=\"{}\"><pre>{}</pre></div>\n",
+            msg.level.name(),
+            escape_html(id),
+            render_html(&render_succinct(msg)))
+}
+
+/// Renders a standalone page with the stylesheet, a table counting the
+/// messages of each level, linking to them, and then the messages, which
+/// are anchored as `diagnostic-1`, `diagnostic-2` and so on.
+pub fn render_html_page(title: &str, msgs: &[CompilerMessage]) -> String {
+    // The levels as they're shown, with the numbers of their messages, in
+    // the order they first come up
+    let mut summary: Vec<(String, Level, Vec<usize>)> = vec![];
+    for (i, msg) in msgs.iter().enumerate() {
+        let name = msg.level.to_string();
+        match summary.iter().position(|row| row.0 == name) {
+            Some(row) => summary[row].2.push(i + 1),
+            None => summary.push((name, msg.level, vec![i + 1])),
+        }
+    }
+
+    let mut html = String::new();
+    write!(html,
+           "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+            <style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+           escape_html(title),
+           STYLESHEET,
+           escape_html(title))
+        .unwrap();
+
+    html.push_str("<table class=\"summary\">\n\
+                   <tr><th>Level</th><th>Count</th><th>Diagnostics</th></tr>\n");
+    for &(ref name, level, ref numbers) in &summary {
+        let links: Vec<String> = numbers.iter()
+            .map(|n| format!("<a href=\"#diagnostic-{}\">{}</a>", n, n))
+            .collect();
+        writeln!(html,
+                 "<tr class=\"{}\"><td class=\"level level-{}\">{}</td><td>{}</td><td>{}</td></tr>",
+                 level.name(),
+                 level.name(),
+                 escape_html(name),
+                 numbers.len(),
+                 links.join(" "))
+            .unwrap();
+    }
+    html.push_str("</table>\n");
+
+    for (i, msg) in msgs.iter().enumerate() {
+        html.push_str(&render_message_html(msg, &format!("diagnostic-{}", i + 1)));
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_utils::test_messages;
+
+    #[test]
+    fn html_escapes() {
+        assert_eq!(escape_html("<a href=\"x\">'&'</a>"),
+                   "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;");
+    }
+
+    #[test]
+    fn html_lines() {
+        let lines = vec![vec![StyledString {
+                                  text: String::from("error"),
+                                  style: Style::Level(Level::Error),
+                              },
+                              StyledString {
+                                  text: String::from(": a < b"),
+                                  style: Style::HeaderMsg,
+                              }],
+                         vec![StyledString {
+                                  text: String::from("  & c"),
+                                  style: Style::NoStyle,
+                              }]];
+        assert_eq!(render_html(&lines),
+                   "<span class=\"level level-error\">error</span>\
+                    <span class=\"header-msg\">: a &lt; b</span>\n  &amp; c\n");
+    }
+
+    #[test]
+    fn html_message() {
+        let msgs = test_messages();
+        let html = render_message_html(&msgs[0], "diagnostic-1");
+        assert!(html.starts_with("<div class=\"diagnostic error\" id=\"diagnostic-1\"><pre>"));
+        assert!(html.contains("<span class=\"line-and-column\">src/&lt;fö o&gt;.rs:2:16</span>"));
+        assert!(html.contains("<span class=\"quotation\">    let 𝔸 = </span>\
+                               <span class=\"underline-secondary\">&quot;😀&quot;</span>\
+                               <span class=\"quotation\"> </span>\
+                               <span class=\"underline-primary\">&lt;</span>\
+                               <span class=\"quotation\"> b &amp;&amp; &#39;c&#39;;</span>"));
+        assert!(html.contains("<span class=\"label-primary\"> &lt;T&gt; here</span>"));
+        assert!(html.contains("<span class=\"label-secondary\">&amp;str</span>"));
+        assert!(!html.contains("<T>"));
+        assert!(html.ends_with("</pre></div>\n"));
+
+        // Without the markup and entities, the text is the same as rendered
+        // for a terminal
+        let lines = render_succinct(&msgs[0]);
+        let text = render_html(&lines)
+            .split('<')
+            .enumerate()
+            .map(|(i, part)| if i == 0 { part } else { &part[part.find('>').unwrap() + 1..] })
+            .collect::<String>()
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&");
+        assert_eq!(text, make_string(&lines));
+    }
+
+    #[test]
+    fn html_page() {
+        let html = render_html_page("Build <1>", &test_messages());
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.contains("<title>Build &lt;1&gt;</title>"));
+        assert!(html.contains(STYLESHEET));
+        assert!(html.contains("<tr class=\"error\"><td class=\"level level-error\">error</td>\
+                               <td>2</td><td><a href=\"#diagnostic-1\">1</a> \
+                               <a href=\"#diagnostic-3\">3</a></td></tr>\n\
+                               <tr class=\"warning\"><td class=\"level level-warning\">warning\
+                               </td><td>1</td><td><a href=\"#diagnostic-2\">2</a></td></tr>\n\
+                               <tr class=\"help\"><td class=\"level level-help\">help</td>\
+                               <td>1</td><td><a href=\"#diagnostic-4\">4</a></td></tr>\n\
+                               <tr class=\"note\"><td class=\"level level-note\">note</td>\
+                               <td>1</td><td><a href=\"#diagnostic-5\">5</a></td></tr>\n\
+                               </table>"));
+        for i in 1..6 {
+            assert!(html.contains(&format!("id=\"diagnostic-{}\"", i)));
+        }
+        assert!(html.ends_with("</body>\n</html>\n"));
+    }
+}
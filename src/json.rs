@@ -0,0 +1,442 @@
+//! A JSON emitter, producing diagnostics in the same shape as rustc's
+//! `--error-format=json`, so that tools can parse the output of both with
+//! the same code.
+
+use std::io::{self, Write};
+use std::vec;
+
+use serde_json;
+
+use styled_buffer::*;
+use compiler_message::*;
+use render_succinct::*;
+use styled_emit::Emitter;
+use codemap::{CodeMap, Span, MacroBacktrace};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The primary error message.
+    pub message: String,
+    pub code: Option<DiagnosticCode>,
+    /// "error: internal compiler error", "error", "warning", "note", "help".
+    pub level: String,
+    pub spans: Vec<DiagnosticSpan>,
+    /// Associated diagnostic messages.
+    pub children: Vec<Diagnostic>,
+    /// The message as it would be rendered as text.
+    pub rendered: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiagnosticSpan {
+    pub file_name: String,
+    /// Byte offsets within the file, not the codemap.
+    pub byte_start: u32,
+    pub byte_end: u32,
+    /// 1-based.
+    pub line_start: usize,
+    pub line_end: usize,
+    /// 1-based, character offset.
+    pub column_start: usize,
+    pub column_end: usize,
+    /// Is this a "primary" span -- meaning the point, or one of the points,
+    /// where the error occurred?
+    pub is_primary: bool,
+    /// Source text from the start of line_start to the end of line_end.
+    pub text: Vec<DiagnosticSpanLine>,
+    /// Label that should be placed at this location (if any)
+    pub label: Option<String>,
+    /// If we are suggesting a replacement, this will contain text
+    /// that should be sliced in atop this span.
+    pub suggested_replacement: Option<String>,
+    /// If the suggestion is approximate
+    pub suggestion_applicability: Option<String>,
+    /// Macro invocations that created the code at this span, if any.
+    pub expansion: Option<Box<DiagnosticSpanMacroExpansion>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiagnosticSpanLine {
+    pub text: String,
+    /// 1-based, character offset in self.text.
+    pub highlight_start: usize,
+    pub highlight_end: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiagnosticSpanMacroExpansion {
+    /// span where macro was applied to generate this code; note that
+    /// this may itself derive from a macro (if
+    /// `span.expansion.is_some()`)
+    pub span: DiagnosticSpan,
+
+    /// name of macro that was applied (e.g., "foo!" or "#[derive(Eq)]")
+    pub macro_decl_name: String,
+
+    /// span where macro was defined (if known)
+    pub def_site_span: Option<DiagnosticSpan>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiagnosticCode {
+    /// The code itself.
+    pub code: String,
+    /// An explanation for the code.
+    pub explanation: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn from_compiler_message(msg: &CompilerMessage) -> Diagnostic {
+        let subs = msg.children
+            .iter()
+            .map(|child| {
+                Diagnostic {
+                    message: child.message.clone(),
+                    code: None,
+                    level: child.level.to_string(),
+                    spans: child.span
+                        .iter()
+                        .flat_map(|span| span.span_labels())
+                        .map(|span_label| {
+                            DiagnosticSpan::from_span(span_label.span,
+                                                      span_label.is_primary,
+                                                      span_label.label,
+                                                      &msg.cm)
+                        })
+                        .collect(),
+                    children: vec![],
+                    rendered: None,
+                }
+            });
+        let suggestions = msg.suggestions
+            .iter()
+            .map(|suggestion| {
+                Diagnostic {
+                    message: suggestion.msg.clone(),
+                    code: None,
+                    level: Level::Help.to_string(),
+                    spans: suggestion.parts
+                        .iter()
+                        .map(|part| {
+                            let mut span =
+                                DiagnosticSpan::from_span(part.span, true, None, &msg.cm);
+                            span.suggested_replacement = Some(part.snippet.clone());
+                            span.suggestion_applicability =
+                                Some(suggestion.applicability.as_str().to_owned());
+                            span
+                        })
+                        .collect(),
+                    children: vec![],
+                    rendered: None,
+                }
+            });
+        let children = subs.chain(suggestions).collect();
+
+        Diagnostic {
+            message: msg.primary_msg.clone(),
+            code: msg.error_code.as_ref().map(|code| {
+                DiagnosticCode {
+                    code: code.clone(),
+                    explanation: None,
+                }
+            }),
+            level: msg.level.to_string(),
+            spans: msg.span_labels
+                .iter()
+                .map(|span_label| {
+                    DiagnosticSpan::from_span(span_label.span,
+                                              span_label.is_primary,
+                                              span_label.label.clone(),
+                                              &msg.cm)
+                })
+                .collect(),
+            children: children,
+            rendered: Some(make_string(&render_succinct(msg))),
+        }
+    }
+}
+
+impl DiagnosticSpan {
+    pub fn from_span(span: Span,
+                     is_primary: bool,
+                     label: Option<String>,
+                     cm: &CodeMap)
+                     -> DiagnosticSpan {
+        let backtrace = cm.macro_backtrace(span).into_iter();
+        DiagnosticSpan::from_span_full(span, is_primary, label, backtrace, cm)
+    }
+
+    fn from_span_full(span: Span,
+                      is_primary: bool,
+                      label: Option<String>,
+                      mut backtrace: vec::IntoIter<MacroBacktrace>,
+                      cm: &CodeMap)
+                      -> DiagnosticSpan {
+        let start = cm.lookup_char_pos(span.lo);
+        let end = cm.lookup_char_pos(span.hi);
+        let backtrace_step = backtrace.next().map(|bt| {
+            let call_site = DiagnosticSpan::from_span_full(bt.call_site,
+                                                           false,
+                                                           None,
+                                                           backtrace,
+                                                           cm);
+            let def_site_span = bt.def_site_span.map(|sp| {
+                DiagnosticSpan::from_span_full(sp, false, None, vec![].into_iter(), cm)
+            });
+            Box::new(DiagnosticSpanMacroExpansion {
+                span: call_site,
+                macro_decl_name: bt.macro_decl_name,
+                def_site_span: def_site_span,
+            })
+        });
+        DiagnosticSpan {
+            file_name: start.file.name.clone(),
+            byte_start: (span.lo - start.file.start_pos).0,
+            byte_end: (span.hi - start.file.start_pos).0,
+            line_start: start.line,
+            line_end: end.line,
+            column_start: start.col.0 + 1,
+            column_end: end.col.0 + 1,
+            is_primary: is_primary,
+            text: DiagnosticSpanLine::from_span(span, cm),
+            label: label,
+            suggested_replacement: None,
+            suggestion_applicability: None,
+            expansion: backtrace_step,
+        }
+    }
+}
+
+impl DiagnosticSpanLine {
+    /// Create a list of DiagnosticSpanLines from span - each line with any part
+    /// of `span` gets a DiagnosticSpanLine, with the highlight indicating the
+    /// `span` within the line.
+    fn from_span(span: Span, cm: &CodeMap) -> Vec<DiagnosticSpanLine> {
+        cm.span_to_lines(span)
+            .map(|lines| {
+                let fm = &*lines.file;
+                lines.lines
+                    .iter()
+                    .map(|line| {
+                        DiagnosticSpanLine {
+                            text: fm.get_line(line.line_index).unwrap_or("").to_owned(),
+                            highlight_start: line.start_col.0 + 1,
+                            highlight_end: line.end_col.0 + 1,
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(|_| vec![])
+    }
+}
+
+/// Serializes the message as a single line of JSON.
+pub fn render_json(msg: &CompilerMessage) -> String {
+    serde_json::to_string(&Diagnostic::from_compiler_message(msg))
+        .expect("diagnostics are always serializable")
+}
+
+/// Reads the diagnostics in `input`, one JSON object per line, either as
+/// written by `render_json` and rustc, or wrapped in the `compiler-message`
+/// records of cargo's `--message-format=json`. Any other lines are skipped.
+pub fn parse_diagnostics(input: &str) -> Vec<Diagnostic> {
+    input.lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter_map(|value| {
+            let value = match value.get("reason") {
+                Some(reason) if reason == "compiler-message" => value["message"].clone(),
+                Some(_) => return None,
+                None => value,
+            };
+            serde_json::from_value(value).ok()
+        })
+        .collect()
+}
+
+pub struct JsonEmitter {
+    dst: Box<dyn Write + Send>,
+}
+
+impl JsonEmitter {
+    pub fn stderr() -> JsonEmitter {
+        JsonEmitter::new(Box::new(io::stderr()))
+    }
+
+    pub fn stdout() -> JsonEmitter {
+        JsonEmitter::new(Box::new(io::stdout()))
+    }
+
+    pub fn new(dst: Box<dyn Write + Send>) -> JsonEmitter {
+        JsonEmitter { dst: dst }
+    }
+}
+
+impl Emitter for JsonEmitter {
+    /// Writes the message as one line of JSON.
+    fn emit(&mut self, msg: &CompilerMessage) -> io::Result<()> {
+        writeln!(self.dst, "{}", render_json(msg))?;
+        self.dst.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+
+    use serde_json::Value;
+
+    use codemap::{ExpnFormat, ExpnInfo, MultiSpan, Name, NameAndSpan};
+    use test_utils::SharedBuf;
+
+    fn test_message(cm: Rc<CodeMap>) -> CompilerMessage {
+        let file_text = "fn foo() {\n    vec.push(vec.pop());\n}\n";
+        let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+        let span_vec0 = cm.span_substr(&foo, "vec", 1).unwrap();
+        let span_vec1 = cm.span_substr(&foo, "vec", 0).unwrap();
+
+        let mut msg = CompilerMessage::new(Level::Error,
+                                           String::from("Not sure what this is"),
+                                           span_vec0,
+                                           Some(String::from("E123")),
+                                           cm);
+        msg.span_label(span_vec0, Some(String::from("primary message")));
+        msg.span_label(span_vec1, None);
+        msg.note(String::from("a note"));
+        msg
+    }
+
+    #[test]
+    fn json_span_note() {
+        let cm = Rc::new(CodeMap::new());
+        let mut msg = test_message(cm.clone());
+        let foo = cm.get_filemap("foo.rs");
+        let mut span = MultiSpan::from_span(cm.span_substr(&foo, "push", 0).unwrap());
+        span.push_span_label(cm.span_substr(&foo, "pop", 0).unwrap(), String::from("here"));
+        msg.span_help(span, String::from("a help"));
+
+        let json: Value = serde_json::from_str(&render_json(&msg)).unwrap();
+        let children = json["children"].as_array().unwrap();
+        assert_eq!(children.len(), 2);
+        let help = &children[1];
+        assert_eq!(help["level"], "help");
+        assert_eq!(help["message"], "a help");
+        let spans = help["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["label"], "here");
+        assert_eq!(spans[0]["is_primary"], false);
+        assert_eq!(spans[1]["byte_start"], 19);
+        assert_eq!(spans[1]["is_primary"], true);
+    }
+
+    #[test]
+    fn json_shape() {
+        // Put another file first so that file-relative offsets are checked
+        let cm = Rc::new(CodeMap::new());
+        cm.new_filemap_and_lines("bar.rs", "fn bar() {}\n");
+        let msg = test_message(cm);
+
+        let json: Value = serde_json::from_str(&render_json(&msg)).unwrap();
+
+        assert_eq!(json["message"], "Not sure what this is");
+        assert_eq!(json["level"], "error");
+        assert_eq!(json["code"]["code"], "E123");
+        assert_eq!(json["code"]["explanation"], Value::Null);
+        assert_eq!(json["rendered"].as_str().unwrap(),
+                   make_string(&render_succinct(&msg)));
+
+        let spans = json["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        let primary = &spans[0];
+        assert_eq!(primary["file_name"], "foo.rs");
+        assert_eq!(primary["byte_start"], 24);
+        assert_eq!(primary["byte_end"], 27);
+        assert_eq!(primary["line_start"], 2);
+        assert_eq!(primary["line_end"], 2);
+        assert_eq!(primary["column_start"], 14);
+        assert_eq!(primary["column_end"], 17);
+        assert_eq!(primary["is_primary"], true);
+        assert_eq!(primary["label"], "primary message");
+        assert_eq!(primary["suggested_replacement"], Value::Null);
+        assert_eq!(primary["expansion"], Value::Null);
+        assert_eq!(primary["text"][0]["text"], "    vec.push(vec.pop());");
+        assert_eq!(primary["text"][0]["highlight_start"], 14);
+        assert_eq!(primary["text"][0]["highlight_end"], 17);
+        assert_eq!(spans[1]["is_primary"], false);
+        assert_eq!(spans[1]["label"], Value::Null);
+
+        let children = json["children"].as_array().unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0]["level"], "note");
+        assert_eq!(children[0]["message"], "a note");
+        assert_eq!(children[0]["spans"], Value::Array(vec![]));
+        assert_eq!(children[0]["rendered"], Value::Null);
+    }
+
+    #[test]
+    fn json_suggestion() {
+        let cm = Rc::new(CodeMap::new());
+        let mut msg = test_message(cm.clone());
+        let foo = cm.get_filemap("foo.rs");
+        let span_pop = cm.span_substr(&foo, "pop", 0).unwrap();
+        msg.span_suggestion(span_pop,
+                            String::from("try popping from the front"),
+                            String::from("pop_front"),
+                            Applicability::MaybeIncorrect);
+
+        let json: Value = serde_json::from_str(&render_json(&msg)).unwrap();
+        let children = json["children"].as_array().unwrap();
+        assert_eq!(children.len(), 2);
+        let help = &children[1];
+        assert_eq!(help["level"], "help");
+        assert_eq!(help["message"], "try popping from the front");
+        let spans = help["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0]["byte_start"], 28);
+        assert_eq!(spans[0]["byte_end"], 31);
+        assert_eq!(spans[0]["suggested_replacement"], "pop_front");
+        assert_eq!(spans[0]["suggestion_applicability"], "MaybeIncorrect");
+    }
+
+    #[test]
+    fn json_macro_expansion() {
+        let cm = Rc::new(CodeMap::new());
+        let file_text = "foo!();\nmacro_rules! foo { () => { bar() } }\n";
+        let fm = cm.new_filemap_and_lines("foo.rs", file_text);
+        let call_site = cm.span_substr(&fm, "foo!()", 0).unwrap();
+        let def_site = cm.span_substr(&fm, "macro_rules! foo { () => { bar() } }", 0).unwrap();
+        let expn_id = cm.record_expansion(ExpnInfo {
+            call_site: call_site,
+            callee: NameAndSpan {
+                format: ExpnFormat::MacroBang(Name(0)),
+                allow_internal_unstable: false,
+                span: Some(def_site),
+            },
+        });
+        let bar = Span { expn_id: expn_id, ..cm.span_substr(&fm, "bar()", 0).unwrap() };
+
+        let span = DiagnosticSpan::from_span(bar, true, None, &cm);
+        assert_eq!(span.line_start, 2);
+        let expansion = span.expansion.unwrap();
+        assert_eq!(expansion.span.byte_start, 0);
+        assert_eq!(expansion.span.byte_end, 6);
+        assert_eq!(expansion.def_site_span.unwrap().byte_start, 8);
+        assert_eq!(expansion.span.expansion, None);
+    }
+
+    #[test]
+    fn json_emitter_writes_one_line() {
+        let out = SharedBuf::default();
+        let mut emitter = JsonEmitter::new(Box::new(out.clone()));
+        let msg = test_message(Rc::new(CodeMap::new()));
+        emitter.emit(&msg).unwrap();
+        emitter.emit(&msg).unwrap();
+
+        let out = out.text();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let parsed: Diagnostic = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(parsed, Diagnostic::from_compiler_message(&msg));
+    }
+}
@@ -1,79 +1,91 @@
-#![feature(question_mark)]
-#![feature(range_contains)]
+//! Renders compiler-style diagnostics: a `CompilerMessage` is built against a
+//! `CodeMap` holding the source files, turned into styled lines with
+//! `render_succinct`, or into a line per location with `render_short`, and
+//! written out by an `Emitter`, usually through a `Handler` which counts the
+//! messages and stops the compilation when there are errors. `render_json`
+//! serializes the same message in the shape of rustc's `--error-format=json`,
+//! and `to_compiler_messages` turns such JSON back into messages, while
+//! `render_sarif` writes a set of messages as a SARIF log for code scanning,
+//! `render_html_page` as a web page for build reports, and
+//! `to_lsp_diagnostics` turns them into Language Server Protocol diagnostics
+//! for editors, which a `LanguageServer` publishes as the open documents
+//! change. A `Theme` maps the styles of the rendered lines to terminal colors
+//! and attributes, and can be loaded from a file.
+//! `apply_suggestions` applies the machine-applicable suggestions of a set of
+//! messages to their source files. A `Registry` holds the long-form
+//! explanations of error codes. The `sync` module has a `SyncCodeMap` for
+//! front ends which build messages on several threads.
 
 extern crate term;
-
-use std::io::{self, Write};
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate unicode_width;
+#[cfg(test)]
+extern crate jsonschema;
+
+#[cfg(test)]
 use std::rc::Rc;
 
+#[cfg(test)]
+mod test_utils;
+
 mod styled_buffer;
-use styled_buffer::*;
+pub use styled_buffer::{make_string, Level, Style, StyledString};
 
 mod compiler_message;
-use compiler_message::*;
+pub use compiler_message::{Applicability, CodeSuggestion, CompilerMessage, SubDiagnostic,
+                           SubstitutionPart};
 
 mod render_succinct;
-use render_succinct::*;
+pub use render_succinct::{render_succinct, render_succinct_with_config, RenderConfig};
+
+mod render_short;
+pub use render_short::render_short;
+
+pub mod theme;
+pub use theme::{Color, ColorDepth, TextStyle, Theme};
 
 mod styled_emit;
-use styled_emit::*;
-
-mod codemap;
-use codemap::*;
-
-
-
-trait CodeMapExtension {
-    fn span_substr(&self,
-                   file: &Rc<FileMap>,
-                   source_text: &str,
-                   substring: &str,
-                   n: usize)
-                   -> Span;
-}
-
-impl CodeMapExtension for CodeMap {
-    fn span_substr(&self,
-                   file: &Rc<FileMap>,
-                   source_text: &str,
-                   substring: &str,
-                   n: usize)
-                   -> Span {
-        let mut i = 0;
-        let mut hi = 0;
-        loop {
-            let offset = source_text[hi..].find(substring).unwrap_or_else(|| {
-                panic!("source_text `{}` does not have {} occurrences of `{}`, only {}",
-                       source_text,
-                       n,
-                       substring,
-                       i);
-            });
-            let lo = hi + offset;
-            hi = lo + substring.len();
-            if i == n {
-                let span = Span {
-                    lo: BytePos(lo as u32 + file.start_pos.0),
-                    hi: BytePos(hi as u32 + file.start_pos.0),
-                    expn_id: NO_EXPANSION,
-                };
-                assert_eq!(&self.span_to_snippet(span).unwrap()[..], substring);
-                return span;
-            }
-            i += 1;
-        }
-    }
-}
+pub use styled_emit::{emit, AnsiWriter, CaptureEmitter, ColorConfig, Destination, Emitter,
+                      EmitterWriter};
+
+pub mod json;
+pub use json::{parse_diagnostics, render_json, JsonEmitter};
+
+pub mod html;
+pub use html::{render_html, render_html_page, render_message_html};
+
+pub mod sarif;
+pub use sarif::{render_sarif, SarifEmitter};
+
+pub mod lsp;
+pub use lsp::to_lsp_diagnostics;
+
+pub mod lsp_server;
+pub use lsp_server::LanguageServer;
+
+pub mod replay;
+pub use replay::to_compiler_messages;
+
+pub mod fix;
+pub use fix::{apply_suggestions, FixResult};
+
+pub mod handler;
+pub use handler::{FatalError, Handler, HandlerFlags};
+
+pub mod registry;
+pub use registry::Registry;
+
+pub mod codemap;
+pub use codemap::{BytePos, CharPos, CodeMap, FileLoader, FileMap, Loc, MultiSpan, RealFileLoader,
+                  Span, SpanLabel, Utf16Pos, DUMMY_SP, NO_EXPANSION};
+
+pub mod sync;
+pub use sync::{MessageSink, PendingMessage, SyncCodeMap, SyncFileMap};
 
-fn make_string(lines: Vec<Vec<StyledString>>) -> String {
-    lines.iter()
-        .flat_map(|rl| {
-            rl.iter()
-                .map(|s| &s.text[..])
-                .chain(Some("\n"))
-        })
-        .collect()
-}
 
 #[test]
 fn test_ellipsis() {
@@ -96,8 +108,8 @@ fn foo() {
 "#;
     let cm = Rc::new(CodeMap::new());
     let foo = cm.new_filemap_and_lines("foo.rs", file_text);
-    let span_vec1 = cm.span_substr(&foo, file_text, "vec", 0);
-    let span_vec0 = cm.span_substr(&foo, file_text, "vec", 1);
+    let span_vec1 = cm.span_substr(&foo, "vec", 0).unwrap();
+    let span_vec0 = cm.span_substr(&foo, "vec", 1).unwrap();
     let error_code = Some("E123".to_string());
 
     let mut err = CompilerMessage::new(Level::Error,
@@ -110,7 +122,7 @@ fn foo() {
     err.span_label(span_vec1, Some(String::from("secondary message")));
 
     let msg = render_succinct(&err);
-    let text = make_string(msg);
+    let text = make_string(&msg);
 
     assert_eq!(&text[..],
                &r#"
@@ -140,8 +152,8 @@ fn foo() {
 "#;
     let cm = Rc::new(CodeMap::new());
     let foo = cm.new_filemap_and_lines("foo.rs", file_text);
-    let span_vec1 = cm.span_substr(&foo, file_text, "vec", 2);
-    let span_vec0 = cm.span_substr(&foo, file_text, "vec", 4);
+    let span_vec1 = cm.span_substr(&foo, "vec", 2).unwrap();
+    let span_vec0 = cm.span_substr(&foo, "vec", 4).unwrap();
     let error_code = Some("E123".to_string());
 
     let mut err = CompilerMessage::new(Level::Warning,
@@ -154,7 +166,7 @@ fn foo() {
     err.span_label(span_vec1, Some(String::from("secondary message")));
 
     let msg = render_succinct(&err);
-    let text = make_string(msg);
+    let text = make_string(&msg);
 
     assert_eq!(&text[..],
                &r#"
@@ -193,9 +205,9 @@ fn bar() {
     let cm = Rc::new(CodeMap::new());
     let bar = cm.new_filemap_and_lines("bar.rs", file_text2);
     let foo = cm.new_filemap_and_lines("foo.rs", file_text);
-    let span_vec1 = cm.span_substr(&foo, file_text, "vec", 0);
-    let span_vec0 = cm.span_substr(&foo, file_text, "vec", 1);
-    let span_vec2 = cm.span_substr(&bar, file_text2, "vec2", 1);
+    let span_vec1 = cm.span_substr(&foo, "vec", 0).unwrap();
+    let span_vec0 = cm.span_substr(&foo, "vec", 1).unwrap();
+    let span_vec2 = cm.span_substr(&bar, "vec2", 1).unwrap();
     let error_code = Some("E123".to_string());
 
     let mut err = CompilerMessage::new(Level::Warning,
@@ -209,7 +221,7 @@ fn bar() {
     err.span_label(span_vec2, Some(String::from("tertiary message")));
 
     let msg = render_succinct(&err);
-    let text = make_string(msg);
+    let text = make_string(&msg);
 
     assert_eq!(&text[..],
                &r#"
@@ -237,8 +249,8 @@ fn foo() {
 "#;
     let cm = Rc::new(CodeMap::new());
     let foo = cm.new_filemap_and_lines("foo.rs", file_text);
-    let span_vec1 = cm.span_substr(&foo, file_text, "vec", 0);
-    let span_vec0 = cm.span_substr(&foo, file_text, "vec", 1);
+    let span_vec1 = cm.span_substr(&foo, "vec", 0).unwrap();
+    let span_vec0 = cm.span_substr(&foo, "vec", 1).unwrap();
     let error_code = Some("E123".to_string());
 
     let mut err = CompilerMessage::new(Level::Error,
@@ -252,7 +264,7 @@ fn foo() {
     err.note(String::from("Are you sure you want to call it `vec`?"));
 
     let msg = render_succinct(&err);
-    let text = make_string(msg);
+    let text = make_string(&msg);
 
     assert_eq!(&text[..],
                &r#"
@@ -266,4 +278,622 @@ error: Not sure what this is [E123]
   |>
   => note: Are you sure you want to call it `vec`?
 "#[1..]);
-}
\ No newline at end of file
+}
+#[test]
+fn test_span_note() {
+    let file_text = r#"
+fn foo() {
+    let first = &mut vec;
+    let second = &mut vec;
+    first.push(1);
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let bar = cm.new_filemap_and_lines("bar.rs", "pub fn vec() {}\n");
+    let span_first = cm.span_substr(&foo, "&mut vec", 0).unwrap();
+    let span_second = cm.span_substr(&foo, "&mut vec", 1).unwrap();
+    let span_bar = cm.span_substr(&bar, "vec", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("cannot borrow `vec` mutably twice"),
+                                       span_second,
+                                       None,
+                                       cm);
+
+    let mut first_borrow = MultiSpan::from_span(span_first);
+    first_borrow.push_span_label(span_first, String::from("first borrow"));
+    err.span_label(span_second, Some(String::from("second borrow")));
+    err.span_note(first_borrow, String::from("the first borrow occurs here"));
+    err.note(String::from("borrows are exclusive"));
+    err.help(String::from("consider cloning"));
+    err.span_warn(span_bar, String::from("`vec` is also a function"));
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: cannot borrow `vec` mutably twice
+ --> foo.rs:4:17
+  |>
+4 |>    let second = &mut vec;
+  |>                 ^^^^^^^^ second borrow
+  |>
+  => note: the first borrow occurs here
+  |>
+3 |>    let first = &mut vec;
+  |>                ^^^^^^^^ first borrow
+  |>
+  => note: borrows are exclusive
+  => help: consider cloning
+  => warning: `vec` is also a function
+  |>
+ ::: bar.rs
+  |>
+1 |>pub fn vec() {}
+  |>       ^^^
+"#[1..]);
+}
+#[test]
+fn test_wide_chars() {
+    let file_text = r#"
+fn foo() {
+    let 変数 = 値 + 1;
+    println!("🦀 {}", 変数);
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let span_value = cm.span_substr(&foo, "値", 0).unwrap();
+    let span_var = cm.span_substr(&foo, "変数", 1).unwrap();
+    let span_str = cm.span_substr(&foo, "\"🦀 {}\"", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("cannot find value `値` in this scope"),
+                                       span_value,
+                                       None,
+                                       cm);
+
+    err.span_label(span_value, Some(String::from("not found in this scope")));
+    err.span_label(span_str, None);
+    err.span_label(span_var, Some(String::from("used here")));
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: cannot find value `値` in this scope
+ --> foo.rs:3:13
+  |>
+3 |>    let 変数 = 値 + 1;
+  |>               ^^ not found in this scope
+4 |>    println!("🦀 {}", 変数);
+  |>             -------  ---- used here
+"#[1..]);
+}
+#[test]
+fn test_combining_chars() {
+    let file_text = "let cafe\u{301} = nai\u{308}ve;\n";
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let span_naive = cm.span_substr(&foo, "nai\u{308}ve", 0).unwrap();
+    let span_semi = cm.span_substr(&foo, ";", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("expected expression"),
+                                       span_naive,
+                                       None,
+                                       cm);
+
+    err.span_label(span_naive, None);
+    err.span_label(span_semi, Some(String::from("here")));
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               r#"
+error: expected expression
+ --> foo.rs:1:12
+  |>
+1 |>let café = naïve;
+  |>           ^^^^^- here
+"#[1..]
+                   .replace("café", "cafe\u{301}")
+                   .replace("naïve", "nai\u{308}ve"));
+}
+#[test]
+fn test_tab_expansion() {
+    let file_text = "all:\n\tgcc -o\tfoo foo.c\n";
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("Makefile", file_text);
+    let span_gcc = cm.span_substr(&foo, "gcc", 0).unwrap();
+    let span_foo = cm.span_substr(&foo, "foo", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("missing separator"),
+                                       span_foo,
+                                       None,
+                                       cm);
+
+    err.span_label(span_gcc, None);
+    err.span_label(span_foo, Some(String::from("here")));
+
+    let text = make_string(&render_succinct(&err));
+    assert_eq!(&text[..],
+               &r#"
+error: missing separator
+ --> Makefile:2:8
+  |>
+2 |>    gcc -o  foo foo.c
+  |>    ---     ^^^ here
+"#[1..]);
+
+    let config = RenderConfig { tab_width: 8, ..RenderConfig::default() };
+    let text = make_string(&render_succinct_with_config(&err, &config));
+    assert_eq!(&text[..],
+               &r#"
+error: missing separator
+ --> Makefile:2:8
+  |>
+2 |>        gcc -o  foo foo.c
+  |>        ---     ^^^ here
+"#[1..]);
+}
+#[test]
+fn test_suggestion_replacement() {
+    let file_text = r#"
+fn foo() {
+    let x = vec.pop().unwrap();
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let span_pop = cm.span_substr(&foo, "pop", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("no method named `pop` found"),
+                                       span_pop,
+                                       None,
+                                       cm);
+
+    err.span_label(span_pop, None);
+    err.span_suggestion(span_pop,
+                        String::from("there is a method with a similar name"),
+                        String::from("pop_front"),
+                        Applicability::MaybeIncorrect);
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: no method named `pop` found
+ --> foo.rs:3:16
+  |>
+3 |>    let x = vec.pop().unwrap();
+  |>                ^^^
+  |>
+  => help: there is a method with a similar name
+  |>
+3 |>    let x = vec.pop_front().unwrap();
+  |>                ~~~~~~~~~
+"#[1..]);
+}
+#[test]
+fn test_suggestion_insertion() {
+    let file_text = r#"
+fn foo() {
+    let x = 1;
+    x = 2;
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let span_x = cm.span_substr(&foo, "x", 0).unwrap();
+    let span_assign = cm.span_substr(&foo, "x = 2", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("cannot assign twice to immutable variable"),
+                                       span_assign,
+                                       None,
+                                       cm);
+
+    err.span_label(span_assign, None);
+    err.span_suggestion(Span { hi: span_x.lo, ..span_x },
+                        String::from("make this binding mutable"),
+                        String::from("mut "),
+                        Applicability::MachineApplicable);
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: cannot assign twice to immutable variable
+ --> foo.rs:4:4
+  |>
+4 |>    x = 2;
+  |>    ^^^^^
+  |>
+  => help: make this binding mutable
+  |>
+3 |>    let mut x = 1;
+  |>        ++++
+"#[1..]);
+}
+#[test]
+fn test_multiline_span() {
+    let file_text = r#"
+fn foo() {
+    vec.push(1);
+}
+
+fn bar() {
+    vec.pop();
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let fn_foo = cm.span_substr(&foo, "fn foo() {\n    vec.push(1);\n}", 0).unwrap();
+    let vec_pop = cm.span_substr(&foo, "vec", 1).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("Not sure what this is"),
+                                       fn_foo,
+                                       None,
+                                       cm);
+
+    err.span_label(fn_foo, Some(String::from("primary message")));
+    err.span_label(vec_pop, Some(String::from("secondary message")));
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: Not sure what this is
+ --> foo.rs:2:0
+  |>
+2 |> / fn foo() {
+3 |> |     vec.push(1);
+4 |> | }
+  |> \_^ primary message
+...
+7 |>       vec.pop();
+  |>       --- secondary message
+"#[1..]);
+}
+
+#[test]
+fn test_multiline_span_mid_line() {
+    let file_text = r#"
+fn foo() {
+    let x = Foo {
+        a: 1,
+    };
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let literal = cm.span_substr(&foo, "Foo {\n        a: 1,\n    }", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("Not sure what this is"),
+                                       literal,
+                                       None,
+                                       cm);
+
+    err.span_label(literal, Some(String::from("primary message")));
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: Not sure what this is
+ --> foo.rs:3:12
+  |>
+3 |>       let x = Foo {
+  |> ______________^
+4 |> |         a: 1,
+5 |> |     };
+  |> \_____^ primary message
+"#[1..]);
+}
+
+#[test]
+fn test_multiline_span_elided() {
+    let file_text = r#"
+fn foo() {
+    vec.push(1);
+    vec.push(2);
+    vec.push(3);
+    vec.push(4);
+    vec.push(5);
+    vec.push(6);
+    vec.push(7);
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let body = cm.span_substr(&foo, "{", 0).unwrap();
+    let body = Span { hi: cm.span_substr(&foo, "}", 0).unwrap().hi, ..body };
+    let push = cm.span_substr(&foo, "push", 3).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("Not sure what this is"),
+                                       body,
+                                       None,
+                                       cm);
+
+    err.span_label(body, Some(String::from("primary message")));
+    err.span_label(push, Some(String::from("secondary message")));
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: Not sure what this is
+  --> foo.rs:2:9
+   |>
+2  |>   fn foo() {
+   |> ___________^
+3  |> |     vec.push(1);
+...   |
+6  |> |     vec.push(4);
+   |> |         ---- secondary message
+...   |
+9  |> |     vec.push(7);
+10 |> | }
+   |> \_^ primary message
+"#[1..]);
+}
+
+#[test]
+fn test_nested_multiline_spans() {
+    let file_text = r#"
+fn foo() {
+    if true {
+        vec.push(1);
+    }
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let fn_foo = cm.span_substr(&foo, "fn foo() {\n    if true {\n        vec.push(1);\n    }\n}", 0).unwrap();
+    let if_true = cm.span_substr(&foo, "if true {\n        vec.push(1);\n    }", 0).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("Not sure what this is"),
+                                       if_true,
+                                       None,
+                                       cm);
+
+    err.span_label(if_true, Some(String::from("primary message")));
+    err.span_label(fn_foo, Some(String::from("secondary message")));
+
+    let msg = render_succinct(&err);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: Not sure what this is
+ --> foo.rs:3:4
+  |>
+2 |> /   fn foo() {
+3 |> | /     if true {
+4 |> | |         vec.push(1);
+5 |> | |     }
+  |> | \_____^ primary message
+6 |> |   }
+  |> \___- secondary message
+"#[1..]);
+}
+
+/// An error inside `inner!`, which is invoked by `outer!`, which is invoked
+/// from `main`.
+#[cfg(test)]
+fn nested_macro_error() -> CompilerMessage {
+    use codemap::{ExpnInfo, MacroBang, Name, NameAndSpan};
+
+    let file_text = r#"
+macro_rules! outer {
+    () => { inner!() }
+}
+macro_rules! inner {
+    () => { bar() }
+}
+fn main() {
+    outer!();
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let outer_def = cm.span_substr(&foo, "macro_rules! outer {\n    () => { inner!() }\n}", 0)
+        .unwrap();
+    let inner_def = cm.span_substr(&foo, "macro_rules! inner {\n    () => { bar() }\n}", 0)
+        .unwrap();
+    let outer_expn = cm.record_expansion(ExpnInfo {
+        call_site: cm.span_substr(&foo, "outer!()", 0).unwrap(),
+        callee: NameAndSpan {
+            format: MacroBang(Name(0)),
+            allow_internal_unstable: false,
+            span: Some(outer_def),
+        },
+    });
+    let inner_expn = cm.record_expansion(ExpnInfo {
+        call_site: Span {
+            expn_id: outer_expn,
+            ..cm.span_substr(&foo, "inner!()", 0).unwrap()
+        },
+        callee: NameAndSpan {
+            format: MacroBang(Name(1)),
+            allow_internal_unstable: false,
+            span: Some(inner_def),
+        },
+    });
+    let span_bar = Span { expn_id: inner_expn, ..cm.span_substr(&foo, "bar", 0).unwrap() };
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("cannot find function `bar`"),
+                                       span_bar,
+                                       None,
+                                       cm);
+    err.span_label(span_bar, Some(String::from("not found in this scope")));
+    err
+}
+
+#[test]
+fn test_macro_invocation() {
+    let msg = render_succinct(&nested_macro_error());
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &concat!(r#"
+error: cannot find function `bar`
+ --> foo.rs:6:12
+  |>
+6 |>    () => { bar() }
+  |>            ^^^ not found in this scope
+...
+9 |>    outer!();
+  |>    -------- in this macro invocation
+  |>
+  => note: this error originates in the macro `Name(1)!` "#,
+                        "which comes from the expansion of the macro `Name(0)!`\n")[1..]);
+}
+
+#[test]
+fn test_macro_backtrace() {
+    let config = RenderConfig { macro_backtrace: true, ..RenderConfig::default() };
+    let msg = render_succinct_with_config(&nested_macro_error(), &config);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: cannot find function `bar`
+ --> foo.rs:6:12
+  |>
+3 |>    () => { inner!() }
+  |>            -------- in this macro invocation (#2)
+...
+6 |>    () => { bar() }
+  |>            ^^^ not found in this scope
+...
+9 |>    outer!();
+  |>    -------- in this macro invocation (#1)
+  |>
+  => note: in this expansion of `Name(0)!` (#1)
+  |>
+2 |> / macro_rules! outer {
+3 |> |     () => { inner!() }
+4 |> | }
+  |> \_^
+  |>
+  => note: in this expansion of `Name(1)!` (#2)
+  |>
+5 |> / macro_rules! inner {
+6 |> |     () => { bar() }
+7 |> | }
+  |> \_^
+"#[1..]);
+}
+
+#[test]
+fn test_map_to_callsites() {
+    let config = RenderConfig {
+        map_to_callsites: true,
+        macro_origin_note: false,
+        ..RenderConfig::default()
+    };
+    let mut err = nested_macro_error();
+    let span_inner = err.cm.with_expn_info(err.primary_span.expn_id, |ei| ei.unwrap().call_site);
+    err.span_label(span_inner, Some(String::from("`bar` is called from `inner!`")));
+
+    let msg = render_succinct_with_config(&err, &config);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: cannot find function `bar`
+ --> foo.rs:9:4
+  |>
+9 |>    outer!();
+  |>    ^^^^^^^^ not found in this scope, `bar` is called from `inner!`
+"#[1..]);
+
+    // The message can ask for the spans to stay where they are
+    err.keep_expansion_spans = true;
+    let msg = render_succinct_with_config(&err, &config);
+    let text = make_string(&msg);
+
+    assert_eq!(&text[..],
+               &r#"
+error: cannot find function `bar`
+ --> foo.rs:6:12
+  |>
+3 |>    () => { inner!() }
+  |>            -------- `bar` is called from `inner!`
+...
+6 |>    () => { bar() }
+  |>            ^^^ not found in this scope
+...
+9 |>    outer!();
+  |>    -------- in this macro invocation
+"#[1..]);
+}
+
+#[test]
+fn test_short() {
+    let file_text = r#"
+fn foo() {
+    let first = &mut vec;
+    let second = &mut vec;
+}
+"#;
+    let cm = Rc::new(CodeMap::new());
+    let foo = cm.new_filemap_and_lines("foo.rs", file_text);
+    let span_first = cm.span_substr(&foo, "&mut vec", 0).unwrap();
+    let span_second = cm.span_substr(&foo, "&mut vec", 1).unwrap();
+
+    let mut err = CompilerMessage::new(Level::Error,
+                                       String::from("cannot borrow `vec` mutably twice"),
+                                       span_second,
+                                       Some(String::from("E0499")),
+                                       cm.clone());
+    err.span_label(span_second, Some(String::from("second borrow")));
+    err.span_label(span_first, Some(String::from("first borrow")));
+    err.note(String::from("borrows are exclusive"));
+
+    let mut warning = CompilerMessage::new(Level::Warning,
+                                           String::from("unused borrows"),
+                                           span_first,
+                                           None,
+                                           cm.clone());
+    warning.span_label(span_first, None);
+    warning.span_label(span_second, None);
+    warning.span_labels[1].is_primary = true;
+
+    let summary = CompilerMessage::new(Level::Error,
+                                       String::from("aborting due to previous error"),
+                                       DUMMY_SP,
+                                       None,
+                                       cm);
+
+    let msgs: Vec<_> = [err, warning, summary].iter().flat_map(render_short).collect();
+    let text = make_string(&msgs);
+
+    assert_eq!(&text[..],
+               &r#"
+foo.rs:4:18: error[E0499]: cannot borrow `vec` mutably twice
+foo.rs:3:17: warning: unused borrows
+foo.rs:4:18: warning: unused borrows
+error: aborting due to previous error
+"#[1..]);
+    assert_eq!(msgs[0][0].style, Style::LineAndColumn);
+    assert_eq!(msgs[0][1].style, Style::Level(Level::Error));
+}
@@ -0,0 +1,354 @@
+//! Turns messages into the diagnostics of the Language Server Protocol, for
+//! editors. Lines are 0-based and columns count UTF-16 code units, as the
+//! protocol has it, so both are looked up with `CodeMap::lookup_utf16_pos`.
+//!
+//! The notes, helps and suggestions of a message are folded into the text of
+//! the diagnostic, since the protocol has nowhere else to put them, while the
+//! other labelled spans become its related information.
+
+use std::env;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+use styled_buffer::Level;
+use compiler_message::CompilerMessage;
+use codemap::{CodeMap, Span, Utf16Pos, DUMMY_SP};
+use sarif::to_uri;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// 0-based.
+    pub line: usize,
+    /// 0-based, in UTF-16 code units.
+    pub character: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub uri: String,
+    pub range: Range,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticSeverity {
+    Error = 1,
+    Warning = 2,
+    Information = 3,
+    Hint = 4,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostic {
+    /// The primary span.
+    pub range: Range,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub severity: Option<DiagnosticSeverity>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub code: Option<String>,
+    /// The tool the diagnostic comes from, eg) "rustc".
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub source: Option<String>,
+    /// The primary message and label, followed by a line per note, help and
+    /// suggestion.
+    pub message: String,
+    /// The other spans, with their labels as the messages.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub related_information: Vec<DiagnosticRelatedInformation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticRelatedInformation {
+    pub location: Location,
+    pub message: String,
+}
+
+/// The parameters of a `textDocument/publishDiagnostics` notification, which
+/// replaces every diagnostic of the document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PublishDiagnosticsParams {
+    pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<i64>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Serialize for DiagnosticSeverity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for DiagnosticSeverity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DiagnosticSeverity, D::Error> {
+        match u8::deserialize(deserializer)? {
+            1 => Ok(DiagnosticSeverity::Error),
+            2 => Ok(DiagnosticSeverity::Warning),
+            3 => Ok(DiagnosticSeverity::Information),
+            4 => Ok(DiagnosticSeverity::Hint),
+            n => Err(D::Error::custom(format!("unknown diagnostic severity {}", n))),
+        }
+    }
+}
+
+impl DiagnosticSeverity {
+    pub fn from_level(level: Level) -> DiagnosticSeverity {
+        match level {
+            Level::Bug | Level::Fatal | Level::PhaseFatal | Level::Error => {
+                DiagnosticSeverity::Error
+            }
+            Level::Warning => DiagnosticSeverity::Warning,
+            Level::Help => DiagnosticSeverity::Hint,
+            Level::Note | Level::FailureNote | Level::Cancelled => DiagnosticSeverity::Information,
+        }
+    }
+}
+
+impl Position {
+    pub fn from_utf16_pos(pos: Utf16Pos) -> Position {
+        Position {
+            line: pos.line,
+            character: pos.col,
+        }
+    }
+
+    pub fn to_utf16_pos(self) -> Utf16Pos {
+        Utf16Pos {
+            line: self.line,
+            col: self.character,
+        }
+    }
+}
+
+impl Range {
+    pub fn from_span(span: Span, cm: &CodeMap) -> Range {
+        Range {
+            start: Position::from_utf16_pos(cm.lookup_utf16_pos(span.lo)),
+            end: Position::from_utf16_pos(cm.lookup_utf16_pos(span.hi)),
+        }
+    }
+}
+
+impl Location {
+    pub fn from_span(span: Span, cm: &CodeMap) -> Location {
+        Location {
+            uri: document_uri(&cm.span_to_filename(span)),
+            range: Range::from_span(span, cm),
+        }
+    }
+}
+
+impl Diagnostic {
+    /// The diagnostic of a message, which is in the document of its first
+    /// primary span, or None when the message has no span at all.
+    pub fn from_compiler_message(msg: &CompilerMessage) -> Option<(String, Diagnostic)> {
+        let primary = msg.span_labels.iter().position(|sl| sl.is_primary);
+        let (span, label) = match primary {
+            Some(i) => (msg.span_labels[i].span, msg.span_labels[i].label.as_ref()),
+            None if !msg.primary_span.source_equal(&DUMMY_SP) => (msg.primary_span, None),
+            None => return None,
+        };
+
+        let mut message = msg.primary_msg.clone();
+        if let Some(label) = label {
+            message.push('\n');
+            message.push_str(label);
+        }
+        for child in &msg.children {
+            message.push_str(&format!("\n{}: {}", child.level, child.message));
+        }
+        for suggestion in &msg.suggestions {
+            message.push_str(&format!("\n{}: {}", Level::Help, suggestion.msg));
+            if suggestion.parts.len() == 1 {
+                message.push_str(&format!(": `{}`", suggestion.parts[0].snippet));
+            }
+        }
+
+        let related_information = msg.span_labels
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| Some(i) != primary)
+            .map(|(_, sl)| {
+                DiagnosticRelatedInformation {
+                    location: Location::from_span(sl.span, &msg.cm),
+                    message: sl.label.clone().unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        let location = Location::from_span(span, &msg.cm);
+        Some((location.uri,
+              Diagnostic {
+                  range: location.range,
+                  severity: Some(DiagnosticSeverity::from_level(msg.level)),
+                  code: msg.error_code.clone(),
+                  source: None,
+                  message: message,
+                  related_information: related_information,
+              }))
+    }
+}
+
+/// The diagnostics of the messages grouped by document, in the order the
+/// documents first come up. Messages without a span are left out, as
+/// editors have nowhere to show them.
+pub fn to_lsp_diagnostics(msgs: &[CompilerMessage]) -> Vec<PublishDiagnosticsParams> {
+    let mut documents: Vec<PublishDiagnosticsParams> = vec![];
+    for (uri, diagnostic) in msgs.iter().filter_map(Diagnostic::from_compiler_message) {
+        match documents.iter().position(|document| document.uri == uri) {
+            Some(i) => documents[i].diagnostics.push(diagnostic),
+            None => {
+                documents.push(PublishDiagnosticsParams {
+                    uri: uri,
+                    version: None,
+                    diagnostics: vec![diagnostic],
+                })
+            }
+        }
+    }
+    documents
+}
+
+/// The URI of the document a file came from. Files named after their URI,
+/// as a language server names the documents it's sent, keep their name,
+/// and paths become `file:` URIs.
+pub fn document_uri(name: &str) -> String {
+    if has_scheme(name) {
+        return name.to_owned();
+    }
+    let path = Path::new(name);
+    if path.is_absolute() || name.as_bytes().get(1) == Some(&b':') {
+        return to_uri(name);
+    }
+    match env::current_dir() {
+        Ok(dir) => to_uri(&dir.join(path).to_string_lossy()),
+        Err(_) => to_uri(name),
+    }
+}
+
+/// Whether `name` starts with a URI scheme, which takes more than a single
+/// letter so as not to be taken for a Windows drive.
+fn has_scheme(name: &str) -> bool {
+    match name.find(':') {
+        Some(end) if end > 1 => {
+            let scheme = &name[..end];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+            scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{self, Value};
+
+    use test_utils::test_messages;
+
+    fn range(start: (usize, usize), end: (usize, usize)) -> Range {
+        Range {
+            start: Position {
+                line: start.0,
+                character: start.1,
+            },
+            end: Position {
+                line: end.0,
+                character: end.1,
+            },
+        }
+    }
+
+    #[test]
+    fn lsp_diagnostics() {
+        let foo = document_uri("src/<fö o>.rs");
+        let documents = to_lsp_diagnostics(&test_messages());
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].uri, foo);
+        assert_eq!(documents[0].diagnostics.len(), 3);
+        assert_eq!(documents[1].uri, "untitled:bar");
+        assert_eq!(documents[1].diagnostics.len(), 1);
+
+        let error = &documents[0].diagnostics[0];
+        // 𝔸 and 😀 each take two UTF-16 code units
+        assert_eq!(error.range, range((1, 18), (1, 19)));
+        assert_eq!(error.severity, Some(DiagnosticSeverity::Error));
+        assert_eq!(error.code, Some(String::from("E0369")));
+        assert_eq!(error.message,
+                   "can't compare `&str` with `<T>`\n\
+                    <T> here\n\
+                    note: `<` needs `PartialOrd`\n\
+                    help: try comparing for equality: `==`");
+        assert_eq!(error.related_information,
+                   vec![DiagnosticRelatedInformation {
+                            location: Location {
+                                uri: foo.clone(),
+                                range: range((1, 13), (1, 17)),
+                            },
+                            message: String::from("&str"),
+                        },
+                        DiagnosticRelatedInformation {
+                            location: Location {
+                                uri: String::from("untitled:bar"),
+                                range: range((0, 3), (0, 6)),
+                            },
+                            message: String::new(),
+                        }]);
+
+        assert_eq!(documents[0].diagnostics[1].message, "can't compare `&str` with `<T>` again");
+        let note = &documents[0].diagnostics[2];
+        assert_eq!(note.severity, Some(DiagnosticSeverity::Information));
+        assert_eq!(note.message, "defined here");
+        assert_eq!(note.range, range((1, 8), (1, 10)));
+        assert_eq!(documents[1].diagnostics[0].severity, Some(DiagnosticSeverity::Warning));
+    }
+
+    #[test]
+    fn lsp_json_shape() {
+        let documents = to_lsp_diagnostics(&test_messages());
+        let json: Value = serde_json::to_value(&documents[1]).unwrap();
+        assert_eq!(json,
+                   json!({
+                       "uri": "untitled:bar",
+                       "diagnostics": [{
+                           "range": {
+                               "start": { "line": 0, "character": 3 },
+                               "end": { "line": 0, "character": 6 }
+                           },
+                           "severity": 2,
+                           "message": "unused function"
+                       }]
+                   }));
+        let error = serde_json::to_value(&documents[0].diagnostics[0]).unwrap();
+        assert_eq!(error["relatedInformation"][0]["location"]["range"]["end"]["character"], 17);
+
+        let parsed: PublishDiagnosticsParams = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, documents[1]);
+
+        // Leaving out the severity leaves it to the client
+        let mut unknown = documents[1].diagnostics[0].clone();
+        unknown.severity = None;
+        let json = serde_json::to_value(&unknown).unwrap();
+        assert!(json.get("severity").is_none());
+        assert_eq!(serde_json::from_value::<Diagnostic>(json).unwrap(), unknown);
+    }
+
+    #[test]
+    fn lsp_document_uri() {
+        assert_eq!(document_uri("file:///src/a.rs"), "file:///src/a.rs");
+        assert_eq!(document_uri("untitled:Untitled-1"), "untitled:Untitled-1");
+        assert_eq!(document_uri("/src/a b.rs"), "file:///src/a%20b.rs");
+        assert_eq!(document_uri("C:\\src\\a.rs"), "file:///C:/src/a.rs");
+        let dir = env::current_dir().unwrap();
+        assert_eq!(document_uri("src/a.rs"), to_uri(&dir.join("src/a.rs").to_string_lossy()));
+    }
+}
@@ -0,0 +1,603 @@
+//! A small language server, which checks the documents an editor has open
+//! and publishes the messages of the check as diagnostics.
+//!
+//! The checking is left to a closure, given the `CodeMap` of the open
+//! documents and the document which was opened or changed, eg)
+//!
+//! ```no_run
+//! # use error_reporter::{CompilerMessage, Level, LanguageServer};
+//! # use std::io;
+//! let mut server = LanguageServer::new("todo-checker", |cm, fm| {
+//!     let mut msgs = vec![];
+//!     if let Some(span) = cm.span_substr(fm, "TODO", 0) {
+//!         msgs.push(CompilerMessage::new(Level::Warning,
+//!                                        String::from("unfinished code"),
+//!                                        span,
+//!                                        None,
+//!                                        cm.clone()));
+//!     }
+//!     msgs
+//! });
+//! let stdin = io::stdin();
+//! server.run(stdin.lock(), io::stdout()).unwrap();
+//! ```
+//!
+//! The server speaks JSON-RPC over the input and output it's given, as
+//! editors do over stdio. Documents are named after their URIs in the
+//! `CodeMap`, which is built again whenever one of them changes.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+
+use serde_json::{self, Value};
+
+use compiler_message::CompilerMessage;
+use codemap::{CodeMap, FileMap, Pos};
+use lsp::{to_lsp_diagnostics, Diagnostic, PublishDiagnosticsParams, Range};
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const SERVER_NOT_INITIALIZED: i64 = -32002;
+
+/// Incremental changes, which send only the edited ranges.
+const TEXT_DOCUMENT_SYNC_INCREMENTAL: u8 = 2;
+
+pub struct LanguageServer<C> {
+    name: String,
+    checker: C,
+    /// The open documents by URI.
+    documents: BTreeMap<String, Document>,
+    cm: Rc<CodeMap>,
+    /// The diagnostics of the last check of each open document, which may
+    /// point into other documents as well.
+    diagnostics: BTreeMap<String, Vec<PublishDiagnosticsParams>>,
+    /// The documents which were last sent diagnostics, which must be cleared
+    /// when they have none.
+    published: BTreeSet<String>,
+    initialized: bool,
+    shutdown: bool,
+}
+
+struct Document {
+    version: i64,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenTextDocumentParams {
+    text_document: TextDocumentItem,
+}
+
+#[derive(Deserialize)]
+struct TextDocumentItem {
+    uri: String,
+    version: i64,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidChangeTextDocumentParams {
+    text_document: VersionedTextDocumentIdentifier,
+    content_changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Deserialize)]
+struct VersionedTextDocumentIdentifier {
+    uri: String,
+    version: i64,
+}
+
+#[derive(Deserialize)]
+struct TextDocumentContentChangeEvent {
+    /// The range replaced, or None when the whole text is.
+    range: Option<Range>,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidCloseTextDocumentParams {
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Deserialize)]
+struct TextDocumentIdentifier {
+    uri: String,
+}
+
+/// An error to answer a request with.
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+impl<C> LanguageServer<C>
+    where C: FnMut(&Rc<CodeMap>, &Rc<FileMap>) -> Vec<CompilerMessage>
+{
+    /// A server calling itself `name`, which checks documents with `checker`.
+    pub fn new(name: &str, checker: C) -> LanguageServer<C> {
+        LanguageServer {
+            name: name.to_owned(),
+            checker: checker,
+            documents: BTreeMap::new(),
+            cm: Rc::new(CodeMap::new()),
+            diagnostics: BTreeMap::new(),
+            published: BTreeSet::new(),
+            initialized: false,
+            shutdown: false,
+        }
+    }
+
+    /// Serves the messages read from `input` until the client sends `exit`
+    /// or closes it. Messages which can't be read are answered with an error
+    /// and skipped.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        loop {
+            let replies = match read_message(&mut input) {
+                Ok(None) => return Ok(()),
+                Ok(Some(content)) => {
+                    match serde_json::from_str::<Value>(&content) {
+                        Ok(ref msg) if msg["method"] == "exit" => return Ok(()),
+                        Ok(msg) => self.handle(msg),
+                        Err(err) => {
+                            vec![error_response(Value::Null, PARSE_ERROR, &err.to_string())]
+                        }
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                    vec![error_response(Value::Null, PARSE_ERROR, &err.to_string())]
+                }
+                Err(err) => return Err(err),
+            };
+            for reply in &replies {
+                write_message(&mut output, reply)?;
+            }
+        }
+    }
+
+    /// Handles a request or notification, returning the messages to send
+    /// back. Responses from the client are ignored, as the server makes no
+    /// requests.
+    pub fn handle(&mut self, msg: Value) -> Vec<Value> {
+        let method = match msg["method"].as_str() {
+            Some(method) => method.to_owned(),
+            None => return vec![],
+        };
+        let id = match msg.get("id") {
+            Some(id) => id.clone(),
+            None => {
+                let params = msg.get("params").cloned().unwrap_or(Value::Null);
+                return self.handle_notification(&method, params);
+            }
+        };
+
+        match self.handle_request(&method) {
+            Ok(result) => {
+                vec![json!({
+                         "jsonrpc": "2.0",
+                         "id": id,
+                         "result": result,
+                     })]
+            }
+            Err(err) => vec![error_response(id, err.code, &err.message)],
+        }
+    }
+
+    fn handle_request(&mut self, method: &str) -> Result<Value, ResponseError> {
+        if self.shutdown {
+            return Err(ResponseError {
+                code: INVALID_REQUEST,
+                message: String::from("the server is shutting down"),
+            });
+        }
+        if !self.initialized && method != "initialize" {
+            return Err(ResponseError {
+                code: SERVER_NOT_INITIALIZED,
+                message: String::from("the server is not initialized"),
+            });
+        }
+
+        match method {
+            "initialize" => {
+                self.initialized = true;
+                Ok(json!({
+                    "capabilities": {
+                        "textDocumentSync": {
+                            "openClose": true,
+                            "change": TEXT_DOCUMENT_SYNC_INCREMENTAL,
+                        },
+                    },
+                    "serverInfo": { "name": self.name },
+                }))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            _ => {
+                Err(ResponseError {
+                    code: METHOD_NOT_FOUND,
+                    message: format!("unknown method `{}`", method),
+                })
+            }
+        }
+    }
+
+    /// Notifications get no response, not even an error, so those which
+    /// can't be handled are dropped.
+    fn handle_notification(&mut self, method: &str, params: Value) -> Vec<Value> {
+        if !self.initialized || self.shutdown {
+            return vec![];
+        }
+
+        match method {
+            "textDocument/didOpen" => {
+                let params: DidOpenTextDocumentParams = match serde_json::from_value(params) {
+                    Ok(params) => params,
+                    Err(_) => return vec![],
+                };
+                let document = params.text_document;
+                self.documents.insert(document.uri.clone(),
+                                      Document {
+                                          version: document.version,
+                                          text: document.text,
+                                      });
+                self.check(&document.uri)
+            }
+            "textDocument/didChange" => {
+                let params: DidChangeTextDocumentParams = match serde_json::from_value(params) {
+                    Ok(params) => params,
+                    Err(_) => return vec![],
+                };
+                let uri = params.text_document.uri;
+                match self.documents.get_mut(&uri) {
+                    Some(document) => {
+                        document.version = params.text_document.version;
+                        for change in &params.content_changes {
+                            apply_change(&uri, &mut document.text, change);
+                        }
+                    }
+                    None => return vec![],
+                }
+                self.check(&uri)
+            }
+            "textDocument/didClose" => {
+                let params: DidCloseTextDocumentParams = match serde_json::from_value(params) {
+                    Ok(params) => params,
+                    Err(_) => return vec![],
+                };
+                let uri = params.text_document.uri;
+                if self.documents.remove(&uri).is_none() {
+                    return vec![];
+                }
+                self.rebuild_codemap();
+                let mut uris: BTreeSet<String> = self.diagnostics
+                    .remove(&uri)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|document| document.uri)
+                    .collect();
+                uris.insert(uri);
+                self.publish(uris)
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Checks the document at `uri`, which must be open, and publishes the
+    /// diagnostics of the documents its check found any in, or found some in
+    /// the last time.
+    fn check(&mut self, uri: &str) -> Vec<Value> {
+        self.rebuild_codemap();
+        let fm = self.cm.get_filemap(uri);
+        let msgs = (self.checker)(&self.cm, &fm);
+
+        let documents = to_lsp_diagnostics(&msgs);
+        let mut uris: BTreeSet<String> = documents.iter().map(|d| d.uri.clone()).collect();
+        if let Some(last) = self.diagnostics.insert(uri.to_owned(), documents) {
+            uris.extend(last.into_iter().map(|document| document.uri));
+        }
+        self.publish(uris)
+    }
+
+    /// Publishes the diagnostics the last checks of the open documents found
+    /// in each of `uris`, clearing those of the documents which no longer
+    /// have any.
+    fn publish(&mut self, uris: BTreeSet<String>) -> Vec<Value> {
+        let mut replies = vec![];
+        for uri in uris {
+            let diagnostics: Vec<Diagnostic> = self.diagnostics
+                .values()
+                .flat_map(|documents| documents.iter())
+                .filter(|document| document.uri == uri)
+                .flat_map(|document| document.diagnostics.iter().cloned())
+                .collect();
+            if diagnostics.is_empty() {
+                if !self.published.remove(&uri) {
+                    continue;
+                }
+            } else {
+                self.published.insert(uri.clone());
+            }
+            let version = self.documents.get(&uri).map(|document| document.version);
+            replies.push(publish_diagnostics(&PublishDiagnosticsParams {
+                uri: uri,
+                version: version,
+                diagnostics: diagnostics,
+            }));
+        }
+        replies
+    }
+
+    fn rebuild_codemap(&mut self) {
+        let cm = CodeMap::new();
+        for (uri, document) in &self.documents {
+            cm.new_filemap_and_lines(uri, &document.text);
+        }
+        self.cm = Rc::new(cm);
+    }
+}
+
+/// Replaces the range of the change in `text`, or the whole of it.
+fn apply_change(uri: &str, text: &mut String, change: &TextDocumentContentChangeEvent) {
+    let range = match change.range {
+        Some(range) => range,
+        None => {
+            *text = change.text.clone();
+            return;
+        }
+    };
+    let cm = CodeMap::new();
+    let fm = cm.new_filemap_and_lines(uri, text);
+    let offset = |pos| (cm.utf16_pos_to_byte_pos(&fm, pos) - fm.start_pos).to_usize();
+    let start = offset(range.start.to_utf16_pos());
+    let end = offset(range.end.to_utf16_pos()).max(start);
+    text.replace_range(start..end, &change.text);
+}
+
+fn publish_diagnostics(params: &PublishDiagnosticsParams) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": params,
+    })
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+/// Reads the content of a message framed by its headers, or None at the
+/// end of the input. A message without a `Content-Length` or whose content
+/// isn't UTF-8 is an `InvalidData` error, after which the next one can be
+/// read, and so is one which ends before its `Content-Length`.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let content_length = content_length.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "message without a Content-Length")
+        })?;
+    // Only what's actually there is buffered, whatever the length claims
+    let mut content = vec![];
+    input.take(content_length as u64).read_to_end(&mut content)?;
+    if content.len() < content_length {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message shorter than its length"));
+    }
+    String::from_utf8(content)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Writes `msg` with the headers framing it.
+pub fn write_message<W: Write>(output: &mut W, msg: &Value) -> io::Result<()> {
+    let content = msg.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::BufReader;
+    use std::thread;
+
+    use styled_buffer::Level;
+
+    fn todo_checker(cm: &Rc<CodeMap>, fm: &Rc<FileMap>) -> Vec<CompilerMessage> {
+        let mut msgs = vec![];
+        let mut n = 0;
+        while let Some(span) = cm.span_substr(fm, "TODO", n) {
+            msgs.push(CompilerMessage::new(Level::Warning,
+                                           String::from("unfinished code"),
+                                           span,
+                                           None,
+                                           cm.clone()));
+            n += 1;
+        }
+        msgs
+    }
+
+    fn range(start: (usize, usize), end: (usize, usize)) -> Value {
+        json!({
+            "start": { "line": start.0, "character": start.1 },
+            "end": { "line": end.0, "character": end.1 },
+        })
+    }
+
+    fn did_change(version: i64, range: Value, text: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": "file:///a.rs", "version": version },
+                "contentChanges": [{ "range": range, "text": text }],
+            },
+        })
+    }
+
+    fn diagnostics(uri: &str, version: Option<i64>, ranges: &[Value]) -> Value {
+        let diagnostics: Vec<Value> = ranges.iter()
+            .map(|range| json!({ "range": range, "severity": 2, "message": "unfinished code" }))
+            .collect();
+        let mut params = json!({ "uri": uri, "diagnostics": diagnostics });
+        if let Some(version) = version {
+            params["version"] = json!(version);
+        }
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": params,
+        })
+    }
+
+    #[test]
+    fn serves_over_pipes() {
+        let (client_reader, server_writer) = io::pipe().unwrap();
+        let (server_reader, mut client_writer) = io::pipe().unwrap();
+        let server = thread::spawn(move || {
+            let mut server = LanguageServer::new("todo", todo_checker);
+            server.run(BufReader::new(server_reader), server_writer)
+        });
+        let mut client_reader = BufReader::new(client_reader);
+        let mut send = |msg: Value| write_message(&mut client_writer, &msg).unwrap();
+        let mut receive = || {
+            let content = read_message(&mut client_reader).unwrap().unwrap();
+            serde_json::from_str::<Value>(&content).unwrap()
+        };
+
+        send(json!({ "jsonrpc": "2.0", "id": 0, "method": "shutdown" }));
+        assert_eq!(receive()["error"]["code"], SERVER_NOT_INITIALIZED);
+
+        send(json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }));
+        let response = receive();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["capabilities"]["textDocumentSync"]["change"], 2);
+        assert_eq!(response["result"]["serverInfo"]["name"], "todo");
+        send(json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }));
+
+        let did_open = |uri: &str, text: &str| {
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {
+                    "textDocument": {
+                        "uri": uri,
+                        "languageId": "rust",
+                        "version": 1,
+                        "text": text,
+                    },
+                },
+            })
+        };
+        let did_close = |uri: &str| {
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didClose",
+                "params": { "textDocument": { "uri": uri } },
+            })
+        };
+        let a = |version, ranges: &[Value]| diagnostics("file:///a.rs", version, ranges);
+        let b = |version, ranges: &[Value]| diagnostics("file:///b.rs", version, ranges);
+
+        send(did_open("file:///a.rs", "let 😀 = TODO;\n"));
+        // The emoji takes two UTF-16 code units
+        assert_eq!(receive(), a(Some(1), &[range((0, 9), (0, 13))]));
+
+        // Replace the TODO, which clears the diagnostics
+        send(did_change(2, range((0, 9), (0, 13)), "1"));
+        assert_eq!(receive(), a(Some(2), &[]));
+
+        // Insert a line after the end of the text
+        send(did_change(3, range((1, 0), (1, 0)), "// TODO: 𝔸\n"));
+        assert_eq!(receive(), a(Some(3), &[range((1, 3), (1, 7))]));
+        send(did_change(4, range((1, 9), (1, 11)), "TODO"));
+        assert_eq!(receive(),
+                   a(Some(4), &[range((1, 3), (1, 7)), range((1, 9), (1, 13))]));
+
+        // Another document only gets diagnostics of its own, and the next
+        // message is the response to the hover, so those of the first one
+        // weren't touched
+        send(did_open("file:///b.rs", "TODO\n"));
+        assert_eq!(receive(), b(Some(1), &[range((0, 0), (0, 4))]));
+        send(did_close("file:///b.rs"));
+        assert_eq!(receive(), b(None, &[]));
+
+        send(json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/hover" }));
+        assert_eq!(receive()["error"]["code"], METHOD_NOT_FOUND);
+
+        // Neither a response from the client nor frames which can't be read
+        // stop the server
+        send(json!({ "jsonrpc": "2.0", "id": 7, "result": null }));
+        write!(client_writer, "Content-Length: 1\r\n\r\n{{").unwrap();
+        let response = receive();
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        write!(client_writer, "Content-Type: text/plain\r\n\r\n").unwrap();
+        assert_eq!(receive()["error"]["code"], PARSE_ERROR);
+        client_writer.write_all(b"Content-Length: 1\r\n\r\n\xff").unwrap();
+        assert_eq!(receive()["error"]["code"], PARSE_ERROR);
+
+        let mut send = |msg: Value| write_message(&mut client_writer, &msg).unwrap();
+        send(did_close("file:///a.rs"));
+        assert_eq!(receive(), a(None, &[]));
+
+        send(json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }));
+        assert_eq!(receive(), json!({ "jsonrpc": "2.0", "id": 3, "result": null }));
+        send(json!({ "jsonrpc": "2.0", "method": "exit" }));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn short_messages() {
+        let mut input = &b"Content-Length: 18446744073709551615\r\n\r\n{}"[..];
+        let err = read_message(&mut input).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn apply_changes() {
+        let mut text = String::from("a😀b\nc\r\nd");
+        let change = |range: Option<Value>, text: &str| {
+            TextDocumentContentChangeEvent {
+                range: range.map(|range| serde_json::from_value(range).unwrap()),
+                text: text.to_owned(),
+            }
+        };
+
+        apply_change("x", &mut text, &change(Some(range((0, 1), (0, 3))), "é"));
+        assert_eq!(text, "aéb\nc\r\nd");
+        // Past the end of the line, before the line ending
+        apply_change("x", &mut text, &change(Some(range((1, 5), (1, 9))), "!"));
+        assert_eq!(text, "aéb\nc!\r\nd");
+        apply_change("x", &mut text, &change(Some(range((0, 2), (2, 0))), ""));
+        assert_eq!(text, "aéd");
+        apply_change("x", &mut text, &change(None, "new"));
+        assert_eq!(text, "new");
+    }
+}
@@ -0,0 +1,254 @@
+//! Long-form explanations of error codes, written in markdown, like the ones
+//! shown by `rustc --explain`.
+//!
+//! A `Registry` is either built from explanations embedded in the binary,
+//! eg)
+//!
+//! ```ignore
+//! let registry = Registry::from_static(&[("E0001", include_str!("E0001.md"))]);
+//! ```
+//!
+//! or loaded at run time from a directory holding one `E0001.md` file per
+//! code. A `Handler` with a registry checks that each message it emits has a
+//! registered code, and points to the explanations when it prints the error
+//! count.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use styled_buffer::{Style, StyledString};
+use compiler_message::CompilerMessage;
+
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    explanations: BTreeMap<String, String>,
+}
+
+/// A message was emitted with a code which isn't in the registry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnregisteredCode(pub String);
+
+impl fmt::Display for UnregisteredCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error code `{}` is not registered", self.0)
+    }
+}
+
+impl Error for UnregisteredCode {}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// A registry of explanations compiled into the binary.
+    pub fn from_static(explanations: &[(&str, &str)]) -> Registry {
+        let mut registry = Registry::new();
+        for &(code, explanation) in explanations {
+            registry.register(code, explanation);
+        }
+        registry
+    }
+
+    /// Reads every `.md` file in `dir`, taking the code from the file name,
+    /// eg) `E0001.md` explains `E0001`.
+    pub fn load_dir(dir: &Path) -> io::Result<Registry> {
+        let mut registry = Registry::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "md") {
+                continue;
+            }
+            let code = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(code) => code.to_owned(),
+                None => continue,
+            };
+            let explanation = fs::read_to_string(&path)?;
+            registry.register(&code, &explanation);
+        }
+        Ok(registry)
+    }
+
+    /// Adds or replaces the explanation of `code`.
+    pub fn register(&mut self, code: &str, explanation: &str) {
+        self.explanations.insert(code.to_owned(), explanation.to_owned());
+    }
+
+    pub fn find_explanation(&self, code: &str) -> Option<&str> {
+        self.explanations.get(code).map(|s| &s[..])
+    }
+
+    /// The registered codes, in order.
+    pub fn codes(&self) -> Vec<&str> {
+        self.explanations.keys().map(|s| &s[..]).collect()
+    }
+
+    /// Checks that the code of `msg`, if it has one, is registered.
+    pub fn validate(&self, msg: &CompilerMessage) -> Result<(), UnregisteredCode> {
+        match msg.error_code {
+            Some(ref code) if !self.explanations.contains_key(code) => {
+                Err(UnregisteredCode(code.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The explanation of `code`, rendered for a terminal.
+    pub fn explain(&self, code: &str) -> Option<Vec<Vec<StyledString>>> {
+        self.find_explanation(code).map(render_markdown)
+    }
+}
+
+/// Renders the markdown of an explanation as styled lines. Headings and
+/// `**strong**` text are bold, and code blocks are indented, without their
+/// fences and, in Rust code, without the lines hidden with `#`.
+pub fn render_markdown(markdown: &str) -> Vec<Vec<StyledString>> {
+    let mut output = vec![];
+    // Inside a code block, whether it's Rust code
+    let mut code_block: Option<bool> = None;
+
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            code_block = match code_block {
+                Some(_) => None,
+                // Rust is the default, and the attributes of doctests like
+                // `compile_fail,E0001` still mark Rust code
+                None => {
+                    Some(info.split(',').all(|attr| {
+                        let attr = attr.trim();
+                        attr.is_empty() || attr == "rust" || attr == "compile_fail" ||
+                        attr == "ignore" || attr == "no_run" || attr == "should_panic" ||
+                        attr.starts_with("edition") || attr.starts_with('E')
+                    }))
+                }
+            };
+            continue;
+        }
+
+        match code_block {
+            Some(is_rust) => {
+                if is_rust && (trimmed == "#" || trimmed.starts_with("# ")) {
+                    continue;
+                }
+                output.push(vec![StyledString {
+                                     text: format!("    {}", line),
+                                     style: Style::Quotation,
+                                 }]);
+            }
+            None => {
+                if trimmed.starts_with('#') {
+                    output.push(vec![StyledString {
+                                         text: trimmed.trim_start_matches('#').trim().to_owned(),
+                                         style: Style::HeaderMsg,
+                                     }]);
+                } else {
+                    output.push(render_inline(line));
+                }
+            }
+        }
+    }
+
+    output
+}
+
+/// Splits a line of text on `**`, making every other part bold.
+fn render_inline(line: &str) -> Vec<StyledString> {
+    line.split("**")
+        .enumerate()
+        .filter(|&(_, text)| !text.is_empty())
+        .map(|(i, text)| {
+            StyledString {
+                text: text.to_owned(),
+                style: if i % 2 == 1 { Style::HeaderMsg } else { Style::NoStyle },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::rc::Rc;
+
+    use codemap::{CodeMap, DUMMY_SP};
+    use styled_buffer::{make_string, Level};
+
+    const E0384: &str = "\
+# Reassigning an immutable variable
+
+An immutable variable was reassigned. **Bindings are immutable by default.**
+
+```compile_fail,E0384
+# fn main() {
+let x = 3;
+x = 5; // error
+# }
+```
+
+```text
+# not hidden
+```
+";
+
+    #[test]
+    fn explain_renders_markdown() {
+        let registry = Registry::from_static(&[("E0384", E0384)]);
+        assert_eq!(registry.codes(), vec!["E0384"]);
+        assert!(registry.explain("E0000").is_none());
+
+        let lines = registry.explain("E0384").unwrap();
+        assert_eq!(make_string(&lines),
+                   "\
+Reassigning an immutable variable
+
+An immutable variable was reassigned. Bindings are immutable by default.
+
+    let x = 3;
+    x = 5; // error
+
+    # not hidden
+");
+        assert_eq!(lines[0][0].style, Style::HeaderMsg);
+        assert_eq!(lines[2][1].style, Style::HeaderMsg);
+        assert_eq!(lines[4][0].style, Style::Quotation);
+    }
+
+    #[test]
+    fn validate_codes() {
+        let registry = Registry::from_static(&[("E0384", E0384)]);
+        let cm = Rc::new(CodeMap::new());
+        let message = |code: Option<&str>| {
+            CompilerMessage::new(Level::Error,
+                                 String::from("oops"),
+                                 DUMMY_SP,
+                                 code.map(String::from),
+                                 cm.clone())
+        };
+
+        assert_eq!(registry.validate(&message(Some("E0384"))), Ok(()));
+        assert_eq!(registry.validate(&message(None)), Ok(()));
+        let err = registry.validate(&message(Some("E9999"))).unwrap_err();
+        assert_eq!(err.to_string(), "error code `E9999` is not registered");
+    }
+
+    #[test]
+    fn load_dir() {
+        let dir = env::temp_dir().join(format!("error_codes_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("E0384.md"), E0384).unwrap();
+        fs::write(dir.join("README.txt"), "not an explanation").unwrap();
+
+        let registry = Registry::load_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let registry = registry.unwrap();
+        assert_eq!(registry.codes(), vec!["E0384"]);
+        assert_eq!(registry.find_explanation("E0384"), Some(E0384));
+    }
+}
@@ -0,0 +1,58 @@
+use styled_buffer::*;
+use compiler_message::*;
+
+/// Renders a message as one line per primary span, without any source, like
+/// rustc's `--error-format=short`, eg)
+///
+/// `foo.rs:3:17: error[E0308]: mismatched types`
+///
+/// Lines and columns are 1-based, as editors and other tools expect them.
+/// Messages without a span get a single line without a location.
+pub fn render_short(msg: &CompilerMessage) -> Vec<Vec<StyledString>> {
+    let locations: Vec<String> = msg.span_labels
+        .iter()
+        .filter(|sl| sl.is_primary)
+        .map(|sl| {
+            let loc = msg.cm.lookup_char_pos(sl.span.lo);
+            format!("{}:{}:{}: ", loc.file.name, loc.line, loc.col.0 + 1)
+        })
+        .collect();
+
+    if locations.is_empty() {
+        return vec![render_short_line(msg, None)];
+    }
+    locations.iter()
+        .map(|location| render_short_line(msg, Some(location)))
+        .collect()
+}
+
+fn render_short_line(msg: &CompilerMessage, location: Option<&str>) -> Vec<StyledString> {
+    let mut line = vec![];
+    if let Some(location) = location {
+        line.push(StyledString {
+            text: location.to_owned(),
+            style: Style::LineAndColumn,
+        });
+    }
+    if msg.level != Level::FailureNote {
+        line.push(StyledString {
+            text: msg.level.to_string(),
+            style: Style::Level(msg.level),
+        });
+        if let Some(ref code) = msg.error_code {
+            line.push(StyledString {
+                text: format!("[{}]", code),
+                style: Style::Level(msg.level),
+            });
+        }
+        line.push(StyledString {
+            text: String::from(": "),
+            style: Style::HeaderMsg,
+        });
+    }
+    line.push(StyledString {
+        text: msg.primary_msg.clone(),
+        style: Style::HeaderMsg,
+    });
+    line
+}
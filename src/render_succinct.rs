@@ -1,12 +1,21 @@
+use std::cmp;
 use std::rc::Rc;
 
+use unicode_width::UnicodeWidthChar;
+
 use styled_buffer::*;
 use compiler_message::*;
-use codemap::{self, Span, CharPos, FileMap};
+use codemap::{BytePos, CodeMap, FileMap, MacroBacktrace, MultiSpan, Pos, Span, SpanLabel,
+              NO_EXPANSION};
+
+/// Multiline spans covering more lines than this have their middle elided,
+/// keeping only the first and last two lines of the span.
+const MAX_MULTILINE_LINES: usize = 6;
 
 struct FileWithAnnotatedLines {
     file: Rc<FileMap>,
     lines: Vec<Line>,
+    multiline_annotations: Vec<MultilineAnnotation>,
 }
 
 #[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
@@ -30,26 +39,137 @@ struct Annotation {
     /// Is this annotation derived from primary span
     is_primary: bool,
 
-    /// Is this a large span minimized down to a smaller span
-    is_minimized: bool,
+    /// Which part of a span this annotation draws
+    annotation_type: AnnotationType,
 
     /// Optional label to display adjacent to the annotation.
     label: Option<String>,
 }
 
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
+enum AnnotationType {
+    /// Annotation under a single line of code
+    Singleline,
+
+    /// The first character of a multiline span. The `usize` is the depth of
+    /// the span's bracket in the gutter, starting at 1.
+    MultilineStart(usize),
+
+    /// The last character of a multiline span, which carries its label.
+    MultilineEnd(usize),
+
+    /// A line somewhere between the start and end of a multiline span.
+    MultilineLine(usize),
+}
+
+#[derive(Clone, Debug)]
+struct MultilineAnnotation {
+    depth: usize,
+    line_start: usize,
+    line_end: usize,
+    start_col: usize,
+    end_col: usize,
+    is_primary: bool,
+    label: Option<String>,
+}
+
+impl MultilineAnnotation {
+    fn overlaps_lines(&self, other: &MultilineAnnotation) -> bool {
+        self.line_start <= other.line_end && other.line_start <= self.line_end
+    }
+
+    /// Does this span pass through the gap between two rendered lines?
+    fn spans_gap(&self, before: usize, after: usize) -> bool {
+        self.line_start <= before && after <= self.line_end
+    }
+
+    fn as_start(&self) -> Annotation {
+        Annotation {
+            start_col: self.start_col,
+            end_col: self.start_col + 1,
+            is_primary: self.is_primary,
+            annotation_type: AnnotationType::MultilineStart(self.depth),
+            label: None,
+        }
+    }
+
+    fn as_end(&self) -> Annotation {
+        let end_col = if self.end_col == 0 { 1 } else { self.end_col };
+        Annotation {
+            start_col: end_col - 1,
+            end_col: end_col,
+            is_primary: self.is_primary,
+            annotation_type: AnnotationType::MultilineEnd(self.depth),
+            label: self.label.clone(),
+        }
+    }
+
+    fn as_line(&self) -> Annotation {
+        Annotation {
+            start_col: 0,
+            end_col: 0,
+            is_primary: self.is_primary,
+            annotation_type: AnnotationType::MultilineLine(self.depth),
+            label: None,
+        }
+    }
+}
+
 fn check_old_school() -> bool {
     false
 }
 
+/// Options for how messages are rendered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RenderConfig {
+    /// Tabs in the source are expanded to the next multiple of this many
+    /// columns. Should be at least 1.
+    pub tab_width: usize,
+    /// Adds a note naming the macro a message comes from, when its primary
+    /// span is in a macro expansion.
+    pub macro_origin_note: bool,
+    /// Shows the whole macro backtrace of primary spans from macro
+    /// expansions instead: every invocation is labelled and numbered, and
+    /// every macro definition is shown in a note of its own.
+    pub macro_backtrace: bool,
+    /// Moves labels which point into macro expansions onto the macro
+    /// invocations in the user's source, unless the message asks to keep
+    /// them with `keep_expansion_spans`.
+    pub map_to_callsites: bool,
+}
+
+impl Default for RenderConfig {
+    fn default() -> RenderConfig {
+        RenderConfig {
+            tab_width: 4,
+            macro_origin_note: true,
+            macro_backtrace: false,
+            map_to_callsites: false,
+        }
+    }
+}
+
 pub fn render_succinct(msg: &CompilerMessage) -> Vec<Vec<StyledString>> {
+    render_succinct_with_config(msg, &RenderConfig::default())
+}
+
+pub fn render_succinct_with_config(msg: &CompilerMessage,
+                                   config: &RenderConfig)
+                                   -> Vec<Vec<StyledString>> {
+    // Spans from macro expansions are dealt with first
+    let resolved = resolve_expansions(msg, config);
+    let msg = resolved.as_ref().unwrap_or(msg);
+
     // Create our styled buffer that we'll use to render the whole error message
     let mut buffer = StyledBuffer::new();
 
     // Header line
     // eg) error: type mismatch [E123]
     // TODO: still needs error number
-    buffer.append(0, &msg.level.to_string(), Style::Level(msg.level));
-    buffer.append(0, ": ", Style::HeaderMsg);
+    if msg.level != Level::FailureNote {
+        buffer.append(0, &msg.level.to_string(), Style::Level(msg.level));
+        buffer.append(0, ": ", Style::HeaderMsg);
+    }
     buffer.append(0, &msg.primary_msg.clone(), Style::HeaderMsg);
     match msg.error_code {
         Some(ref code) => {
@@ -62,16 +182,20 @@ pub fn render_succinct(msg: &CompilerMessage) -> Vec<Vec<StyledString>> {
 
     // Preprocess all the annotations so that they are grouped by file and by line number
     // This helps us quickly iterate over the whole message (including secondary file spans)
-    let mut annotated_files = preprocess_annotations(msg);
+    let mut annotated_files = preprocess_annotations(&msg.cm, &msg.span_labels);
 
     // figure out the largest line number so we can align the line number column
     let max_line_num = get_max_line_num(msg);
     let len_of_max_line_num = max_line_num.to_string().len();
 
-    // Make sure our primary file comes first
-    let primary_lo = msg.cm.lookup_char_pos(msg.primary_span.lo);
-    if let Ok(pos) =
-            annotated_files.binary_search_by(|x| x.file.name.cmp(&primary_lo.file.name)) {
+    // Make sure our primary file comes first. Messages without any spans, like
+    // the summary at the end of a compilation, don't have one.
+    let primary_file = if msg.span_labels.is_empty() {
+        String::new()
+    } else {
+        msg.cm.lookup_char_pos(msg.primary_span.lo).file.name.clone()
+    };
+    if let Ok(pos) = annotated_files.binary_search_by(|x| x.file.name.cmp(&primary_file)) {
         annotated_files.swap(0, pos);
     }
 
@@ -80,22 +204,22 @@ pub fn render_succinct(msg: &CompilerMessage) -> Vec<Vec<StyledString>> {
 
         // print out the span location and spacer before we print the annotated source
         // to do this, we need to know if this span will be primary
-        let is_primary = primary_lo.file.name == annotated_file.file.name;
+        let is_primary = primary_file == annotated_file.file.name;
         if is_primary {
             // remember where we are in the output buffer for easy reference
-            let mut buffer_msg_line_offset = buffer.num_lines();
+            let buffer_msg_line_offset = buffer.num_lines();
 
             buffer.prepend(buffer_msg_line_offset, "--> ", Style::LineNumber);
             let loc = msg.cm.lookup_char_pos(msg.primary_span.lo);
             buffer.append(buffer_msg_line_offset,
                             &format!("{}:{}:{}", loc.file.name, loc.line, loc.col.0),
                             Style::LineAndColumn);
-            for i in 0..len_of_max_line_num {
+            for _ in 0..len_of_max_line_num {
                 buffer.prepend(buffer_msg_line_offset, " ", Style::NoStyle);
             }
         } else {
             // remember where we are in the output buffer for easy reference
-            let mut buffer_msg_line_offset = buffer.num_lines();
+            let buffer_msg_line_offset = buffer.num_lines();
 
             // Add spacing line
             buffer.puts(buffer_msg_line_offset,
@@ -107,79 +231,245 @@ pub fn render_succinct(msg: &CompilerMessage) -> Vec<Vec<StyledString>> {
             buffer.append(buffer_msg_line_offset + 1,
                             &annotated_file.file.name,
                             Style::LineAndColumn);
-            for i in 0..len_of_max_line_num {
+            for _ in 0..len_of_max_line_num {
                 buffer.prepend(buffer_msg_line_offset + 1, " ", Style::NoStyle);
             }
         }
 
-        // Put in the spacer between the location and annotated source
-        let mut buffer_msg_line_offset = buffer.num_lines();
-        buffer.puts(buffer_msg_line_offset,
-                    len_of_max_line_num + 1,
-                    "|>",
-                    Style::LineNumber);
-
-        // Next, output the annotate source for this file
-        for line_idx in 0..annotated_file.lines.len() {
-            render_source_line(msg, &mut buffer,
-                                    annotated_file.file.clone(),
-                                    &annotated_file.lines[line_idx],
-                                    3 + len_of_max_line_num);
-
-            // check to see if we need to print out or elide lines that come between
-            // this annotated line and the next one
-            if line_idx < (annotated_file.lines.len() - 1) {
-                let line_idx_delta = annotated_file.lines[line_idx + 1].line_number -
-                                        annotated_file.lines[line_idx].line_number;
-                if line_idx_delta > 2 {
-                    let last_buffer_line_num = buffer.num_lines();
-                    buffer.puts(last_buffer_line_num, 0, "...", Style::LineNumber);
-                } else if line_idx_delta == 2 {
-                    let unannotated_line = annotated_file.file
-                        .get_line(annotated_file.lines[line_idx].line_number)
-                        .unwrap_or("");
-
-                    let last_buffer_line_num = buffer.num_lines();
-
-                    buffer.puts(last_buffer_line_num,
-                                0,
-                                &(annotated_file.lines[line_idx + 1].line_number - 1)
-                                    .to_string(),
-                                Style::LineNumber);
-                    buffer.puts(last_buffer_line_num,
-                                1 + len_of_max_line_num,
-                                "|>",
-                                Style::LineNumber);
-                    buffer.puts(last_buffer_line_num,
-                                3 + len_of_max_line_num,
-                                &unannotated_line,
-                                Style::Quotation);
-                }
-            }
-        }
+        render_annotated_file(&mut buffer, &annotated_file, len_of_max_line_num, config);
     }
 
-    // write out the notes that don't have a span
-    if !msg.notes.is_empty() {
-        // Put in the spacer in before the notes
-        let mut buffer_msg_line_offset = buffer.num_lines();
-        buffer.puts(buffer_msg_line_offset,
-                    len_of_max_line_num + 1,
-                    "|>",
-                    Style::LineNumber);
+    // write out the children. Those without a span are only a line each, so
+    // consecutive ones share the spacer in front of them
+    let mut after_line_child = false;
+    for child in &msg.children {
+        if !after_line_child {
+            let buffer_msg_line_offset = buffer.num_lines();
+            buffer.puts(buffer_msg_line_offset,
+                        len_of_max_line_num + 1,
+                        "|>",
+                        Style::LineNumber);
+        }
+        render_child(msg,
+                     &mut buffer,
+                     child,
+                     &primary_file,
+                     len_of_max_line_num,
+                     config);
+        after_line_child = child.span.is_none();
     }
-    for note in &msg.notes {
-        let last_buffer_line_num = buffer.num_lines();
 
-        buffer.puts(last_buffer_line_num, 1 + len_of_max_line_num, "=> ", Style::LineNumber);
-        buffer.append(last_buffer_line_num, "note: ", Style::Level(Level::Note));
-        buffer.append(last_buffer_line_num, &note, Style::NoStyle);
+    // write out the suggestions, each showing the source as it would look patched
+    for suggestion in &msg.suggestions {
+        render_suggestion(msg,
+                          &mut buffer,
+                          suggestion,
+                          &primary_file,
+                          len_of_max_line_num,
+                          config);
     }
 
     // final step: take our styled buffer and render it
     buffer.render()
 }
 
+/// Points out where the primary spans of `msg` which come from macro
+/// expansions were expanded, and moves labels out of expansions, as set by
+/// `config`. Returns `None` if there is nothing to change.
+fn resolve_expansions(msg: &CompilerMessage, config: &RenderConfig) -> Option<CompilerMessage> {
+    fn label_once(msg: &mut CompilerMessage, span: Span, label: String) {
+        if !msg.span_labels.iter().any(|sl| sl.span.source_equal(&span)) {
+            msg.span_labels.push(SpanLabel {
+                span: span,
+                is_primary: false,
+                label: Some(label),
+            });
+        }
+    }
+
+    // Primary spans from the same expansion share their backtrace
+    let mut backtraces: Vec<Vec<MacroBacktrace>> = vec![];
+    for span_label in msg.span_labels.iter().filter(|sl| sl.is_primary) {
+        let backtrace = msg.cm.macro_backtrace(span_label.span);
+        let call_sites: Vec<Span> = backtrace.iter().map(|bt| bt.call_site).collect();
+        let seen = backtraces.iter()
+            .any(|other| other.iter().map(|bt| bt.call_site).eq(call_sites.iter().cloned()));
+        if !backtrace.is_empty() && !seen {
+            backtraces.push(backtrace);
+        }
+    }
+    let map_to_callsites = config.map_to_callsites && !msg.keep_expansion_spans &&
+                           msg.span_labels.iter().any(|sl| sl.span.expn_id != NO_EXPANSION);
+    if backtraces.is_empty() && !map_to_callsites {
+        return None;
+    }
+
+    let mut msg = msg.clone();
+    if map_to_callsites {
+        msg.primary_span = msg.cm.source_callsite(msg.primary_span);
+        msg.span_labels = map_labels_to_callsites(&msg.cm, &msg.span_labels);
+    }
+    if config.macro_backtrace {
+        // Number the steps from the user's code inwards
+        let mut step = 0;
+        for backtrace in &backtraces {
+            for trace in backtrace.iter().rev() {
+                step += 1;
+                label_once(&mut msg,
+                           trace.call_site,
+                           format!("in this macro invocation (#{})", step));
+                msg.sub(Level::Note,
+                        format!("in this expansion of `{}` (#{})", trace.macro_decl_name, step),
+                        trace.def_site_span.map(MultiSpan::from_span));
+            }
+        }
+    } else {
+        for backtrace in &backtraces {
+            let outermost = backtrace.last().unwrap();
+            label_once(&mut msg, outermost.call_site, String::from("in this macro invocation"));
+        }
+        if config.macro_origin_note {
+            let kind = match msg.level {
+                Level::Warning => "warning",
+                Level::Note => "note",
+                Level::Help => "help",
+                _ => "error",
+            };
+            let (innermost, outermost) = (&backtraces[0][0], backtraces[0].last().unwrap());
+            let mut note = format!("this {} originates in the macro `{}`",
+                                   kind,
+                                   innermost.macro_decl_name);
+            if outermost.macro_decl_name != innermost.macro_decl_name {
+                note.push_str(&format!(" which comes from the expansion of the macro `{}`",
+                                       outermost.macro_decl_name));
+            }
+            msg.note(note);
+        }
+    }
+    Some(msg)
+}
+
+/// Moves the labels which point into macro expansions onto the invocations
+/// in the user's source. A label which lands on the same span as another one
+/// is merged into it.
+fn map_labels_to_callsites(cm: &CodeMap, span_labels: &[SpanLabel]) -> Vec<SpanLabel> {
+    // Along with each label, whether it was moved
+    let mut mapped: Vec<(SpanLabel, bool)> = vec![];
+    for span_label in span_labels {
+        let span = cm.source_callsite(span_label.span);
+        let moved = !span.source_equal(&span_label.span);
+        let same_span = mapped.iter_mut()
+            .find(|(other, other_moved)| {
+                (moved || *other_moved) && other.span.source_equal(&span)
+            });
+        match same_span {
+            Some((other, _)) => {
+                // Keep the primary label's text first
+                let (first, second) = if span_label.is_primary && !other.is_primary {
+                    (span_label.label.clone(), other.label.take())
+                } else {
+                    (other.label.take(), span_label.label.clone())
+                };
+                other.label = match (first, second) {
+                    (Some(first), Some(second)) if first != second => {
+                        Some(format!("{}, {}", first, second))
+                    }
+                    (first, second) => first.or(second),
+                };
+                other.is_primary |= span_label.is_primary;
+            }
+            None => {
+                mapped.push((SpanLabel {
+                                 span: span,
+                                 is_primary: span_label.is_primary,
+                                 label: span_label.label.clone(),
+                             },
+                             moved))
+            }
+        }
+    }
+    mapped.into_iter().map(|(span_label, _)| span_label).collect()
+}
+
+/// Renders the annotated source lines of one file, starting with the spacer
+/// below its location line.
+fn render_annotated_file(buffer: &mut StyledBuffer,
+                         annotated_file: &FileWithAnnotatedLines,
+                         len_of_max_line_num: usize,
+                         config: &RenderConfig) {
+    // Put in the spacer between the location and annotated source
+    let buffer_msg_line_offset = buffer.num_lines();
+    buffer.puts(buffer_msg_line_offset,
+                len_of_max_line_num + 1,
+                "|>",
+                Style::LineNumber);
+
+    // Multiline spans draw their brackets between the line numbers and the source,
+    // so make room for the deepest one
+    let width_offset = 3 + len_of_max_line_num;
+    let max_depth = annotated_file.multiline_annotations
+        .iter()
+        .map(|ml| ml.depth)
+        .max()
+        .unwrap_or(0);
+    let code_offset = if max_depth == 0 {
+        width_offset
+    } else {
+        gutter_col(width_offset, max_depth) + 2
+    };
+
+    // Next, output the annotate source for this file
+    for line_idx in 0..annotated_file.lines.len() {
+        render_source_line(buffer,
+                           annotated_file.file.clone(),
+                           &annotated_file.lines[line_idx],
+                           width_offset,
+                           code_offset,
+                           config);
+
+        // check to see if we need to print out or elide lines that come between
+        // this annotated line and the next one
+        if line_idx < (annotated_file.lines.len() - 1) {
+            let line_idx_delta = annotated_file.lines[line_idx + 1].line_number -
+                                    annotated_file.lines[line_idx].line_number;
+            if line_idx_delta > 2 {
+                let last_buffer_line_num = buffer.num_lines();
+                buffer.puts(last_buffer_line_num, 0, "...", Style::LineNumber);
+                draw_multiline_gap(buffer,
+                                   last_buffer_line_num,
+                                   annotated_file,
+                                   line_idx,
+                                   width_offset);
+            } else if line_idx_delta == 2 {
+                let unannotated_line = annotated_file.file
+                    .get_line(annotated_file.lines[line_idx].line_number)
+                    .unwrap_or("");
+
+                let last_buffer_line_num = buffer.num_lines();
+
+                buffer.puts(last_buffer_line_num,
+                            0,
+                            &(annotated_file.lines[line_idx + 1].line_number - 1)
+                                .to_string(),
+                            Style::LineNumber);
+                buffer.puts(last_buffer_line_num,
+                            1 + len_of_max_line_num,
+                            "|>",
+                            Style::LineNumber);
+                buffer.puts(last_buffer_line_num,
+                            code_offset,
+                            &expand_tabs(unannotated_line, config.tab_width),
+                            Style::Quotation);
+                draw_multiline_gap(buffer,
+                                   last_buffer_line_num,
+                                   annotated_file,
+                                   line_idx,
+                                   width_offset);
+            }
+        }
+    }
+}
+
 fn get_max_line_num(msg: &CompilerMessage) -> usize {
     let mut max = 0;
     for span_label in &msg.span_labels {
@@ -188,10 +478,25 @@ fn get_max_line_num(msg: &CompilerMessage) -> usize {
             max = hi.line;
         }
     }
+    let child_spans = msg.children.iter().filter_map(|c| c.span.as_ref());
+    for span_label in child_spans.flat_map(|s| s.span_labels()) {
+        let hi = msg.cm.lookup_char_pos(span_label.span.hi);
+        if hi.line > max {
+            max = hi.line;
+        }
+    }
+    for part in msg.suggestions.iter().flat_map(|s| &s.parts) {
+        // Replacements spanning several lines push the lines after them down
+        let hi = msg.cm.lookup_char_pos(part.span.hi);
+        let line = hi.line + part.snippet.matches('\n').count();
+        if line > max {
+            max = line;
+        }
+    }
     max
 }
 
-fn preprocess_annotations(msg: &CompilerMessage) -> Vec<FileWithAnnotatedLines> {
+fn preprocess_annotations(cm: &CodeMap, span_labels: &[SpanLabel]) -> Vec<FileWithAnnotatedLines> {
     fn add_annotation_to_file(file_vec: &mut Vec<FileWithAnnotatedLines>,
                                 file: Rc<FileMap>,
                                 line_number: usize,
@@ -200,19 +505,18 @@ fn preprocess_annotations(msg: &CompilerMessage) -> Vec<FileWithAnnotatedLines>
         for slot in file_vec.iter_mut() {
             // Look through each of our files for the one we're adding to
             if slot.file.name == file.name {
-                // See if we already have a line for it
-                for line_slot in &mut slot.lines {
-                    if line_slot.line_number == line_number {
-                        line_slot.annotations.push(ann);
-                        return;
+                // The lines are kept sorted, so see if we already have one for
+                // it, or else where it goes
+                match slot.lines.binary_search_by_key(&line_number, |l| l.line_number) {
+                    Ok(idx) => slot.lines[idx].annotations.push(ann),
+                    Err(idx) => {
+                        slot.lines.insert(idx,
+                                          Line {
+                                              line_number: line_number,
+                                              annotations: vec![ann],
+                                          })
                     }
                 }
-                // We don't have a line yet, create one
-                slot.lines.push(Line {
-                    line_number: line_number,
-                    annotations: vec![ann],
-                });
-                slot.lines.sort();
                 return;
             }
         }
@@ -223,29 +527,46 @@ fn preprocess_annotations(msg: &CompilerMessage) -> Vec<FileWithAnnotatedLines>
                             line_number: line_number,
                             annotations: vec![ann],
                         }],
+            multiline_annotations: vec![],
         });
     }
 
     let mut output = vec![];
+    let mut multiline_annotations = vec![];
 
-    for span_label in &msg.span_labels {
-        let lo = msg.cm.lookup_char_pos(span_label.span.lo);
-        let hi = msg.cm.lookup_char_pos(span_label.span.hi);
+    for span_label in span_labels {
+        let lo = cm.lookup_char_pos(span_label.span.lo);
+        let mut hi = cm.lookup_char_pos(span_label.span.hi);
 
-        // If the span is multi-line, simplify down to the span of one character
-        let (start_col, mut end_col, is_minimized) = if lo.line != hi.line {
-            (lo.col, CharPos(lo.col.0 + 1), true)
-        } else {
-            (lo.col, hi.col, false)
-        };
+        // A span which ends right after a newline really ends on the line
+        // before, so point at that newline rather than the next line's start.
+        if hi.line > lo.line && hi.col.0 == 0 {
+            hi = cm.lookup_char_pos(BytePos(span_label.span.hi.0 - 1));
+            hi.col.0 += 1;
+        }
+
+        if lo.line != hi.line {
+            multiline_annotations.push((lo.file,
+                                        MultilineAnnotation {
+                                            depth: 0,
+                                            line_start: lo.line,
+                                            line_end: hi.line,
+                                            start_col: lo.col.0,
+                                            end_col: hi.col.0,
+                                            is_primary: span_label.is_primary,
+                                            label: span_label.label.clone(),
+                                        }));
+            continue;
+        }
 
         // Watch out for "empty spans". If we get a span like 6..6, we
         // want to just display a `^` at 6, so convert that to
         // 6..7. This is degenerate input, but it's best to degrade
         // gracefully -- and the parser likes to supply a span like
         // that for EOF, in particular.
-        if start_col == end_col {
-            end_col.0 += 1;
+        let mut end_col = hi.col.0;
+        if lo.col.0 == end_col {
+            end_col += 1;
         }
 
         add_annotation_to_file(&mut output,
@@ -253,27 +574,310 @@ fn preprocess_annotations(msg: &CompilerMessage) -> Vec<FileWithAnnotatedLines>
                                 lo.line,
                                 Annotation {
                                     start_col: lo.col.0,
-                                    end_col: hi.col.0,
+                                    end_col: end_col,
                                     is_primary: span_label.is_primary,
-                                    is_minimized: is_minimized,
+                                    annotation_type: AnnotationType::Singleline,
                                     label: span_label.label.clone(),
                                 });
     }
+
+    // Give every multiline span its own bracket in the gutter. Spans which end
+    // later are placed further out, so that the `\_` closing an inner span
+    // never has to cross the bracket of a span which is still open.
+    multiline_annotations.sort_by(|a, b| {
+        b.1.line_end.cmp(&a.1.line_end).then(a.1.line_start.cmp(&b.1.line_start))
+    });
+    for i in 0..multiline_annotations.len() {
+        let depth = {
+            let (ref file, ref ml) = multiline_annotations[i];
+            multiline_annotations[..i]
+                .iter()
+                .filter(|&&(ref f, ref other)| f.name == file.name && other.overlaps_lines(ml))
+                .map(|&(_, ref other)| other.depth)
+                .max()
+                .unwrap_or(0) + 1
+        };
+        multiline_annotations[i].1.depth = depth;
+    }
+
+    for (file, ml) in multiline_annotations {
+        add_annotation_to_file(&mut output, file.clone(), ml.line_start, ml.as_start());
+        add_annotation_to_file(&mut output, file.clone(), ml.line_end, ml.as_end());
+
+        // Show the whole span if it's short enough, otherwise just the two
+        // lines on either end and let the elision in between show as `...`
+        let middle_lines: Vec<usize> = if ml.line_end - ml.line_start < MAX_MULTILINE_LINES {
+            (ml.line_start + 1..ml.line_end).collect()
+        } else {
+            vec![ml.line_start + 1, ml.line_end - 1]
+        };
+        for line_number in middle_lines {
+            add_annotation_to_file(&mut output, file.clone(), line_number, ml.as_line());
+        }
+
+        let slot = output.iter_mut().find(|slot| slot.file.name == file.name).unwrap();
+        slot.multiline_annotations.push(ml);
+    }
+
+    // Other annotations may have pulled in lines from the elided middle of a
+    // multiline span, which still need its bracket drawn through them
+    for slot in &mut output {
+        for ml in &slot.multiline_annotations {
+            for line in &mut slot.lines {
+                if line.line_number > ml.line_start && line.line_number < ml.line_end {
+                    let ann = ml.as_line();
+                    if !line.annotations.contains(&ann) {
+                        line.annotations.push(ann);
+                    }
+                }
+            }
+        }
+    }
+
     output
 }
 
-fn render_source_line(msg: &CompilerMessage,
-                        buffer: &mut StyledBuffer,
-                        file: Rc<FileMap>,
-                        line: &Line,
-                        width_offset: usize) {
+/// Renders a child message: a `=>` line, followed by the snippets of its span
+/// if it has one.
+fn render_child(msg: &CompilerMessage,
+                buffer: &mut StyledBuffer,
+                child: &SubDiagnostic,
+                primary_file: &str,
+                len_of_max_line_num: usize,
+                config: &RenderConfig) {
+    // eg) => note: the first borrow occurs here
+    let header_line = buffer.num_lines();
+    buffer.puts(header_line, 1 + len_of_max_line_num, "=> ", Style::LineNumber);
+    buffer.append(header_line, &format!("{}: ", child.level), Style::Level(child.level));
+    buffer.append(header_line, &child.message, Style::NoStyle);
+
+    let span = match child.span {
+        Some(ref span) => span,
+        None => return,
+    };
+    for annotated_file in preprocess_annotations(&msg.cm, &span.span_labels()) {
+        // Only point out the file when it isn't the one the message is about
+        if annotated_file.file.name != primary_file {
+            let buffer_msg_line_offset = buffer.num_lines();
+            buffer.puts(buffer_msg_line_offset,
+                        len_of_max_line_num + 1,
+                        "|>",
+                        Style::LineNumber);
+            buffer.prepend(buffer_msg_line_offset + 1, "::: ", Style::LineNumber);
+            buffer.append(buffer_msg_line_offset + 1,
+                          &annotated_file.file.name,
+                          Style::LineAndColumn);
+            for _ in 0..len_of_max_line_num {
+                buffer.prepend(buffer_msg_line_offset + 1, " ", Style::NoStyle);
+            }
+        }
+        render_annotated_file(buffer, &annotated_file, len_of_max_line_num, config);
+    }
+}
+
+/// A run of neighbouring source lines with a suggestion's replacements applied
+struct PatchedHunk {
+    /// The line number of the first line
+    line_number: usize,
+
+    /// The characters of each patched line, each marked with the character
+    /// to underline it with if it comes from a replacement
+    lines: Vec<Vec<(char, Option<char>)>>,
+}
+
+/// A suggestion part along with the lines it starts and ends on
+type PlacedPart<'a> = (&'a SubstitutionPart, usize, usize);
+
+/// Groups the parts of a suggestion by file and then into hunks of nearby
+/// lines, and applies the parts to each hunk.
+fn patch_hunks(msg: &CompilerMessage,
+               suggestion: &CodeSuggestion)
+               -> Vec<(Rc<FileMap>, Vec<PatchedHunk>)> {
+    let mut files: Vec<(Rc<FileMap>, Vec<PlacedPart>)> = vec![];
+    for part in &suggestion.parts {
+        let lo = msg.cm.lookup_char_pos(part.span.lo);
+        let hi = msg.cm.lookup_char_pos(part.span.hi);
+        if lo.file.name != hi.file.name || lo.file.src.is_none() || lo.line == 0 {
+            continue;
+        }
+        match files.iter().position(|(file, _)| file.name == lo.file.name) {
+            Some(idx) => files[idx].1.push((part, lo.line, hi.line)),
+            None => files.push((lo.file, vec![(part, lo.line, hi.line)])),
+        }
+    }
+
+    let mut output = vec![];
+    for (file, mut parts) in files {
+        parts.sort_by_key(|&(part, _, _)| part.span.lo);
+
+        // Parts on the same or adjacent lines are shown together
+        let mut clusters: Vec<Vec<PlacedPart>> = vec![];
+        for part in parts {
+            let joins_last = match clusters.last() {
+                Some(cluster) => part.1 <= cluster.iter().map(|p| p.2).max().unwrap() + 1,
+                None => false,
+            };
+            if joins_last {
+                clusters.last_mut().unwrap().push(part);
+            } else {
+                clusters.push(vec![part]);
+            }
+        }
+
+        let src = file.src.as_ref().unwrap();
+        let hunks = clusters.into_iter()
+            .map(|cluster| {
+                let first_line = cluster[0].1;
+                let last_line = cluster.iter().map(|p| p.2).max().unwrap();
+                let (start, end) = {
+                    let lines = file.lines.borrow();
+                    let last_start = (lines[last_line - 1] - file.start_pos).to_usize();
+                    ((lines[first_line - 1] - file.start_pos).to_usize(),
+                     last_start + file.get_line(last_line - 1).unwrap_or("").len())
+                };
+
+                let mut patched = vec![];
+                let mut cursor = start;
+                for (part, _, _) in cluster {
+                    let lo = (part.span.lo - file.start_pos).to_usize();
+                    let hi = (part.span.hi - file.start_pos).to_usize();
+                    // Overlapping replacements can't be applied together
+                    if lo < cursor {
+                        continue;
+                    }
+                    patched.extend(src[cursor..lo].chars().map(|c| (c, None)));
+                    let marker = if lo == hi { '+' } else { '~' };
+                    patched.extend(part.snippet.chars().map(|c| (c, Some(marker))));
+                    cursor = hi;
+                }
+                if cursor < end {
+                    patched.extend(src[cursor..end].chars().map(|c| (c, None)));
+                }
+
+                PatchedHunk {
+                    line_number: first_line,
+                    lines: patched.split(|&(c, _)| c == '\n').map(|l| l.to_vec()).collect(),
+                }
+            })
+            .collect();
+        output.push((file, hunks));
+    }
+    output
+}
+
+fn render_suggestion(msg: &CompilerMessage,
+                     buffer: &mut StyledBuffer,
+                     suggestion: &CodeSuggestion,
+                     primary_file: &str,
+                     len_of_max_line_num: usize,
+                     config: &RenderConfig) {
+    let width_offset = 3 + len_of_max_line_num;
+
+    // Put in the spacer and the header
+    // eg) => help: try using `pop`
+    let header_line = buffer.num_lines() + 1;
+    buffer.puts(header_line - 1, len_of_max_line_num + 1, "|>", Style::LineNumber);
+    buffer.puts(header_line, 1 + len_of_max_line_num, "=> ", Style::LineNumber);
+    buffer.append(header_line, "help: ", Style::Level(Level::Help));
+    buffer.append(header_line, &suggestion.msg, Style::NoStyle);
+
+    for (file, hunks) in patch_hunks(msg, suggestion) {
+        let mut row = buffer.num_lines();
+        if file.name != primary_file {
+            buffer.puts(row, len_of_max_line_num + 1, "|>", Style::LineNumber);
+            buffer.prepend(row + 1, "::: ", Style::LineNumber);
+            buffer.append(row + 1, &file.name, Style::LineAndColumn);
+            for _ in 0..len_of_max_line_num {
+                buffer.prepend(row + 1, " ", Style::NoStyle);
+            }
+            row += 2;
+        }
+        buffer.puts(row, len_of_max_line_num + 1, "|>", Style::LineNumber);
+
+        for (hunk_idx, hunk) in hunks.iter().enumerate() {
+            if hunk_idx > 0 {
+                let row = buffer.num_lines();
+                buffer.puts(row, 0, "...", Style::LineNumber);
+            }
+            for (line_idx, line) in hunk.lines.iter().enumerate() {
+                let row = buffer.num_lines();
+                buffer.puts(row,
+                            0,
+                            &(hunk.line_number + line_idx).to_string(),
+                            Style::LineNumber);
+                buffer.puts(row, width_offset - 2, "|>", Style::LineNumber);
+
+                // Underline what the suggestion inserts, as wide as it shows up
+                let has_markers = line.iter().any(|&(_, marker)| marker.is_some());
+                if has_markers {
+                    buffer.puts(row + 1, width_offset - 2, "|>", Style::LineNumber);
+                }
+                let (mut cell, mut col) = (width_offset, width_offset);
+                for &(c, marker) in line {
+                    let style = if marker.is_some() {
+                        Style::Addition
+                    } else {
+                        Style::Quotation
+                    };
+                    let width = if c == '\t' {
+                        let width = tab_stop(col - width_offset, config.tab_width);
+                        buffer.puts(row, cell, &" ".repeat(width), style);
+                        cell += width;
+                        width
+                    } else {
+                        buffer.putc(row, cell, c, style);
+                        cell += 1;
+                        char_width(c)
+                    };
+                    if let Some(marker) = marker {
+                        for p in col..col + width {
+                            buffer.putc(row + 1, p, marker, Style::Addition);
+                        }
+                    }
+                    col += width;
+                }
+            }
+        }
+    }
+}
+
+/// Draws the brackets of the multiline spans which pass between the rendered
+/// line at `line_idx` and the one after it, on the elided row `row`.
+fn draw_multiline_gap(buffer: &mut StyledBuffer,
+                      row: usize,
+                      annotated_file: &FileWithAnnotatedLines,
+                      line_idx: usize,
+                      width_offset: usize) {
+    let before = annotated_file.lines[line_idx].line_number;
+    let after = annotated_file.lines[line_idx + 1].line_number;
+    for ml in &annotated_file.multiline_annotations {
+        if ml.spans_gap(before, after) {
+            let style = if ml.is_primary {
+                Style::UnderlinePrimary
+            } else {
+                Style::UnderlineSecondary
+            };
+            buffer.putc(row, gutter_col(width_offset, ml.depth), '|', style);
+        }
+    }
+}
+
+fn render_source_line(buffer: &mut StyledBuffer,
+                      file: Rc<FileMap>,
+                      line: &Line,
+                      width_offset: usize,
+                      code_offset: usize,
+                      config: &RenderConfig) {
     let source_string = file.get_line(line.line_number - 1)
         .unwrap_or("");
 
     let line_offset = buffer.num_lines();
 
     // First create the source line we will highlight.
-    buffer.puts(line_offset, width_offset, &source_string, Style::Quotation);
+    buffer.puts(line_offset,
+                code_offset,
+                &expand_tabs(source_string, config.tab_width),
+                Style::Quotation);
     buffer.puts(line_offset,
                 0,
                 &(line.line_number.to_string()),
@@ -285,6 +889,106 @@ fn render_source_line(msg: &CompilerMessage,
         return;
     }
 
+    // Multiline spans which start at the beginning of the line's code are
+    // bracketed with a `/` next to it:
+    //
+    //      / fn foo() {
+    //      |     bar();
+    //      | }
+    //      \_^ label
+    //
+    // Otherwise the start gets its own underline, connected to the gutter:
+    //
+    //         let x = Foo {
+    //       ____________^
+    //      |      a: 1,
+    //      |  };
+    //      \__^ label
+    for annotation in &line.annotations {
+        let style = underline_style(annotation);
+        match annotation.annotation_type {
+            AnnotationType::MultilineStart(depth) => {
+                if starts_at_indentation(source_string, annotation.start_col) {
+                    buffer.putc(line_offset, gutter_col(width_offset, depth), '/', style);
+                }
+            }
+            AnnotationType::MultilineLine(depth) |
+            AnnotationType::MultilineEnd(depth) => {
+                buffer.putc(line_offset, gutter_col(width_offset, depth), '|', style);
+            }
+            AnnotationType::Singleline => {}
+        }
+    }
+
+    // Only some parts of a multiline span are drawn under the line itself
+    let mut annotations: Vec<Annotation> = line.annotations
+        .iter()
+        .filter(|a| {
+            match a.annotation_type {
+                AnnotationType::Singleline |
+                AnnotationType::MultilineEnd(_) => true,
+                AnnotationType::MultilineStart(_) => {
+                    !starts_at_indentation(source_string, a.start_col)
+                }
+                AnnotationType::MultilineLine(_) => false,
+            }
+        })
+        .cloned()
+        .collect();
+
+    if !annotations.is_empty() {
+        // Sort the annotations by (start, end col)
+        annotations.sort();
+
+        // Color what's underlined in the source line itself
+        for annotation in &annotations {
+            if annotation.annotation_type == AnnotationType::Singleline {
+                let (start, _) = expanded_col(source_string, annotation.start_col, config);
+                let (end, _) = expanded_col(source_string, annotation.end_col, config);
+                for p in start..end {
+                    buffer.set_style(line_offset, code_offset + p, underline_style(annotation));
+                }
+            }
+        }
+
+        // The source line has a char per cell, but everything drawn below it
+        // has to line up with how wide the chars are on screen
+        for annotation in &mut annotations {
+            let (_, start_col) = expanded_col(source_string, annotation.start_col, config);
+            let (_, end_col) = expanded_col(source_string, annotation.end_col, config);
+            annotation.start_col = start_col;
+            annotation.end_col = cmp::max(end_col, start_col + 1);
+        }
+        render_annotations(buffer, annotations, line_offset, width_offset, code_offset);
+    }
+
+    // Finally, continue the brackets of spans which carry on past this line
+    // down through any rows we added underneath it.
+    for row in line_offset + 1..buffer.num_lines() {
+        for annotation in &line.annotations {
+            let style = underline_style(annotation);
+            match annotation.annotation_type {
+                AnnotationType::MultilineLine(depth) => {
+                    buffer.putc(row, gutter_col(width_offset, depth), '|', style);
+                }
+                AnnotationType::MultilineStart(depth) => {
+                    // The row right below a mid-line start holds its connector
+                    if row > line_offset + 1 ||
+                       starts_at_indentation(source_string, annotation.start_col) {
+                        buffer.putc(row, gutter_col(width_offset, depth), '|', style);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn render_annotations(buffer: &mut StyledBuffer,
+                      annotations: Vec<Annotation>,
+                      line_offset: usize,
+                      width_offset: usize,
+                      code_offset: usize) {
     // We want to display like this:
     //
     //      vec.push(vec.pop().unwrap());
@@ -307,9 +1011,26 @@ fn render_source_line(msg: &CompilerMessage,
     // let mut highlight_line = Self::whitespace(&source_string);
     let old_school = check_old_school();
 
-    // Sort the annotations by (start, end col)
-    let mut annotations = line.annotations.clone();
-    annotations.sort();
+    // Draw the `_` connecting the gutter bracket of a multiline span to the
+    // character it starts or ends at. The underlines drawn below take
+    // precedence wherever the two cross.
+    for annotation in &annotations {
+        let style = underline_style(annotation);
+        match annotation.annotation_type {
+            AnnotationType::MultilineStart(depth) => {
+                for p in gutter_col(width_offset, depth)..code_offset + annotation.start_col {
+                    buffer.putc(line_offset + 1, p, '_', style);
+                }
+            }
+            AnnotationType::MultilineEnd(depth) => {
+                buffer.putc(line_offset + 1, gutter_col(width_offset, depth), '\\', style);
+                for p in gutter_col(width_offset, depth) + 1..code_offset + annotation.start_col {
+                    buffer.putc(line_offset + 1, p, '_', style);
+                }
+            }
+            _ => {}
+        }
+    }
 
     // Next, create the highlight line.
     for annotation in &annotations {
@@ -317,7 +1038,7 @@ fn render_source_line(msg: &CompilerMessage,
             for p in annotation.start_col..annotation.end_col {
                 if p == annotation.start_col {
                     buffer.putc(line_offset + 1,
-                                width_offset + p,
+                                code_offset + p,
                                 '^',
                                 if annotation.is_primary {
                                     Style::UnderlinePrimary
@@ -326,7 +1047,7 @@ fn render_source_line(msg: &CompilerMessage,
                                 });
                 } else {
                     buffer.putc(line_offset + 1,
-                                width_offset + p,
+                                code_offset + p,
                                 '~',
                                 if annotation.is_primary {
                                     Style::UnderlinePrimary
@@ -339,24 +1060,14 @@ fn render_source_line(msg: &CompilerMessage,
             for p in annotation.start_col..annotation.end_col {
                 if annotation.is_primary {
                     buffer.putc(line_offset + 1,
-                                width_offset + p,
+                                code_offset + p,
                                 '^',
                                 Style::UnderlinePrimary);
-                    if !annotation.is_minimized {
-                        buffer.set_style(line_offset,
-                                            width_offset + p,
-                                            Style::UnderlinePrimary);
-                    }
                 } else {
                     buffer.putc(line_offset + 1,
-                                width_offset + p,
+                                code_offset + p,
                                 '-',
                                 Style::UnderlineSecondary);
-                    if !annotation.is_minimized {
-                        buffer.set_style(line_offset,
-                                            width_offset + p,
-                                            Style::UnderlineSecondary);
-                    }
                 }
             }
         }
@@ -447,12 +1158,12 @@ fn render_source_line(msg: &CompilerMessage,
         for index in 2..blank_lines {
             if annotation.is_primary {
                 buffer.putc(line_offset + index,
-                            width_offset + annotation.start_col,
+                            code_offset + annotation.start_col,
                             '|',
                             Style::UnderlinePrimary);
             } else {
                 buffer.putc(line_offset + index,
-                            width_offset + annotation.start_col,
+                            code_offset + annotation.start_col,
                             '|',
                             Style::UnderlineSecondary);
             }
@@ -464,12 +1175,12 @@ fn render_source_line(msg: &CompilerMessage,
 
         if annotation.is_primary {
             buffer.puts(line_offset + blank_lines,
-                        width_offset + annotation.start_col,
+                        code_offset + annotation.start_col,
                         annotation.label.as_ref().unwrap(),
                         Style::LabelPrimary);
         } else {
             buffer.puts(line_offset + blank_lines,
-                        width_offset + annotation.start_col,
+                        code_offset + annotation.start_col,
                         annotation.label.as_ref().unwrap(),
                         Style::LabelSecondary);
         }
@@ -481,6 +1192,74 @@ fn render_source_line(msg: &CompilerMessage,
 }
 
 fn overlaps(a1: &Annotation, a2: &Annotation) -> bool {
-    (a2.start_col..a2.end_col).contains(a1.start_col) ||
-    (a1.start_col..a1.end_col).contains(a2.start_col)
+    (a2.start_col <= a1.start_col && a1.start_col < a2.end_col) ||
+    (a1.start_col <= a2.start_col && a2.start_col < a1.end_col)
+}
+
+/// The column of the bracket drawn for a multiline span at `depth`
+fn gutter_col(width_offset: usize, depth: usize) -> usize {
+    width_offset + 2 * depth - 1
+}
+
+fn underline_style(annotation: &Annotation) -> Style {
+    if annotation.is_primary {
+        Style::UnderlinePrimary
+    } else {
+        Style::UnderlineSecondary
+    }
+}
+
+/// The number of cells `c` takes up on screen: 2 for East Asian wide chars,
+/// 0 for combining marks and 1 for everything else.
+fn char_width(c: char) -> usize {
+    // Control chars have no width of their own, but are written out as is
+    UnicodeWidthChar::width(c).unwrap_or(1)
+}
+
+/// The number of columns a tab at `col` takes up to get to the next tab stop.
+fn tab_stop(col: usize, tab_width: usize) -> usize {
+    let tab_width = cmp::max(tab_width, 1);
+    tab_width - col % tab_width
+}
+
+/// Replaces the tabs in `source` with spaces up to the next tab stop.
+fn expand_tabs(source: &str, tab_width: usize) -> String {
+    let mut expanded = String::with_capacity(source.len());
+    let mut col = 0;
+    for c in source.chars() {
+        if c == '\t' {
+            let width = tab_stop(col, tab_width);
+            expanded.extend((0..width).map(|_| ' '));
+            col += width;
+        } else {
+            expanded.push(c);
+            col += char_width(c);
+        }
+    }
+    expanded
+}
+
+/// Where the char at `col` in `source` ends up once its tabs are expanded:
+/// the cell it is put in, one char per cell, and the column it shows up at
+/// on screen. Columns past the end of the line, as for spans pointing at its
+/// newline, count one cell each.
+fn expanded_col(source: &str, col: usize, config: &RenderConfig) -> (usize, usize) {
+    let (mut cell, mut display) = (0, 0);
+    for c in source.chars().take(col) {
+        if c == '\t' {
+            let width = tab_stop(display, config.tab_width);
+            cell += width;
+            display += width;
+        } else {
+            cell += 1;
+            display += char_width(c);
+        }
+    }
+    let past_end = col.saturating_sub(source.chars().count());
+    (cell + past_end, display + past_end)
+}
+
+/// Is there only whitespace before `col` in `source`?
+fn starts_at_indentation(source: &str, col: usize) -> bool {
+    source.chars().take(col).all(char::is_whitespace)
 }
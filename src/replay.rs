@@ -0,0 +1,284 @@
+//! Turns JSON diagnostics, as written by rustc's `--error-format=json` or by
+//! the `JsonEmitter`, back into `CompilerMessage`s, so old logs can be
+//! rendered again.
+//!
+//! The files the diagnostics point into are read through the file loader of
+//! the `CodeMap`. When a file is gone, or no longer has the lines the
+//! diagnostics quote, it's pieced together from those quoted lines instead,
+//! with the lines nobody quoted left blank.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use styled_buffer::Level;
+use compiler_message::*;
+use codemap::{BytePos, CodeMap, FileMap, MultiSpan, Pos, Span, SpanLabel, DUMMY_SP,
+              NO_EXPANSION};
+use json::{Diagnostic, DiagnosticSpan};
+
+/// The lines of a file quoted by the diagnostics, by 1-based line number.
+#[derive(Default)]
+struct QuotedLines {
+    lines: BTreeMap<usize, String>,
+    line_count: usize,
+}
+
+/// Builds a message for each diagnostic, adding the files they point into
+/// to `cm`.
+pub fn to_compiler_messages(cm: &Rc<CodeMap>, diagnostics: &[Diagnostic]) -> Vec<CompilerMessage> {
+    let mut files: BTreeMap<String, QuotedLines> = BTreeMap::new();
+    for diagnostic in diagnostics {
+        collect_quoted_lines(diagnostic, &mut files);
+    }
+
+    let filemaps: BTreeMap<String, Rc<FileMap>> = files.into_iter()
+        .map(|(name, quoted)| {
+            let src = match read_matching_file(cm, &name, &quoted) {
+                Some(src) => src,
+                None => piece_together(&quoted),
+            };
+            let fm = cm.new_filemap_and_lines(&name, &src);
+            (name, fm)
+        })
+        .collect();
+
+    diagnostics.iter()
+        .map(|diagnostic| to_compiler_message(cm, &filemaps, diagnostic))
+        .collect()
+}
+
+fn collect_quoted_lines(diagnostic: &Diagnostic, files: &mut BTreeMap<String, QuotedLines>) {
+    for span in &diagnostic.spans {
+        let quoted = files.entry(span.file_name.clone()).or_default();
+        for (i, line) in span.text.iter().enumerate() {
+            quoted.lines.insert(span.line_start + i, line.text.clone());
+        }
+        quoted.line_count = quoted.line_count.max(span.line_end);
+    }
+    for child in &diagnostic.children {
+        collect_quoted_lines(child, files);
+    }
+}
+
+/// Reads the file through the file loader, if it still has the quoted lines.
+fn read_matching_file(cm: &CodeMap, name: &str, quoted: &QuotedLines) -> Option<String> {
+    let path = Path::new(name);
+    if !cm.file_exists(path) {
+        return None;
+    }
+    let src = cm.read_file(path).ok()?;
+    let lines: Vec<&str> = src.lines().collect();
+    let matches = lines.len() >= quoted.line_count &&
+                  quoted.lines.iter().all(|(&line, text)| lines.get(line - 1) == Some(&&text[..]));
+    if matches { Some(src) } else { None }
+}
+
+fn piece_together(quoted: &QuotedLines) -> String {
+    let mut src = String::new();
+    for line in 1..quoted.line_count + 1 {
+        if let Some(text) = quoted.lines.get(&line) {
+            src.push_str(text);
+        }
+        src.push('\n');
+    }
+    src
+}
+
+fn to_compiler_message(cm: &Rc<CodeMap>,
+                       filemaps: &BTreeMap<String, Rc<FileMap>>,
+                       diagnostic: &Diagnostic)
+                       -> CompilerMessage {
+    let spans: Vec<(Span, &DiagnosticSpan)> = diagnostic.spans
+        .iter()
+        .map(|ds| (to_span(&filemaps[&ds.file_name], ds), ds))
+        .collect();
+    let primary_span = spans.iter()
+        .find(|&&(_, ds)| ds.is_primary)
+        .map_or(DUMMY_SP, |&(span, _)| span);
+
+    let mut msg = CompilerMessage::new(parse_level(&diagnostic.level),
+                                       diagnostic.message.clone(),
+                                       primary_span,
+                                       diagnostic.code.as_ref().map(|code| code.code.clone()),
+                                       cm.clone());
+    msg.span_labels = spans.iter()
+        .map(|&(span, ds)| {
+            SpanLabel {
+                span: span,
+                is_primary: ds.is_primary,
+                label: ds.label.clone(),
+            }
+        })
+        .collect();
+
+    for child in &diagnostic.children {
+        let is_suggestion = !child.spans.is_empty() &&
+                            child.spans.iter().all(|ds| ds.suggested_replacement.is_some());
+        if is_suggestion {
+            let parts = child.spans
+                .iter()
+                .map(|ds| {
+                    (to_span(&filemaps[&ds.file_name], ds),
+                     ds.suggested_replacement.clone().unwrap())
+                })
+                .collect();
+            let applicability = child.spans[0]
+                .suggestion_applicability
+                .as_ref()
+                .map_or(Applicability::Unspecified, |a| parse_applicability(a));
+            msg.multipart_suggestion(child.message.clone(), parts, applicability);
+        } else {
+            let span = if child.spans.is_empty() {
+                None
+            } else {
+                let mut span = MultiSpan::from_spans(child.spans
+                    .iter()
+                    .filter(|ds| ds.is_primary)
+                    .map(|ds| to_span(&filemaps[&ds.file_name], ds))
+                    .collect());
+                for ds in &child.spans {
+                    if let Some(ref label) = ds.label {
+                        span.push_span_label(to_span(&filemaps[&ds.file_name], ds), label.clone());
+                    }
+                }
+                Some(span)
+            };
+            msg.sub(parse_level(&child.level), child.message.clone(), span);
+        }
+    }
+
+    msg
+}
+
+/// The span of `ds` in `fm`, going by its lines and columns, which also work
+/// for files which were pieced together.
+fn to_span(fm: &FileMap, ds: &DiagnosticSpan) -> Span {
+    Span {
+        lo: position(fm, ds.line_start, ds.column_start),
+        hi: position(fm, ds.line_end, ds.column_end),
+        expn_id: NO_EXPANSION,
+    }
+}
+
+/// The position of a 1-based line and character column, kept within the file.
+fn position(fm: &FileMap, line: usize, column: usize) -> BytePos {
+    let lines = fm.lines.borrow();
+    let line_index = line.saturating_sub(1).min(lines.len() - 1);
+    let text = fm.get_line(line_index).unwrap_or("");
+    let offset = text.char_indices()
+        .nth(column.saturating_sub(1))
+        .map_or(text.len(), |(offset, _)| offset);
+    lines[line_index] + BytePos::from_usize(offset)
+}
+
+fn parse_level(level: &str) -> Level {
+    match level {
+        "error: internal compiler error" => Level::Bug,
+        "error" => Level::Error,
+        "warning" => Level::Warning,
+        "help" => Level::Help,
+        "failure-note" => Level::FailureNote,
+        _ => Level::Note,
+    }
+}
+
+fn parse_applicability(applicability: &str) -> Applicability {
+    match applicability {
+        "MachineApplicable" => Applicability::MachineApplicable,
+        "MaybeIncorrect" => Applicability::MaybeIncorrect,
+        "HasPlaceholders" => Applicability::HasPlaceholders,
+        _ => Applicability::Unspecified,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use json::{parse_diagnostics, render_json};
+    use render_succinct::render_succinct;
+    use styled_buffer::make_string;
+    use test_utils::MemLoader;
+
+    const FOO_RS: &str = "\
+fn foo() {
+    let first = &mut vec;
+    // unrelated
+    let second = &mut vec;
+    first.push(1);
+}
+";
+
+    fn test_message() -> CompilerMessage {
+        let cm = Rc::new(CodeMap::new());
+        let foo = cm.new_filemap_and_lines("foo.rs", FOO_RS);
+        let span_first = cm.span_substr(&foo, "&mut vec", 0).unwrap();
+        let span_second = cm.span_substr(&foo, "&mut vec", 1).unwrap();
+
+        let mut msg = CompilerMessage::new(Level::Error,
+                                           String::from("cannot borrow `vec` mutably twice"),
+                                           span_second,
+                                           Some(String::from("E0499")),
+                                           cm.clone());
+        msg.span_label(span_second, Some(String::from("second borrow")));
+        msg.span_label(span_first, Some(String::from("first borrow")));
+        msg.span_note(span_first, String::from("the first borrow occurs here"));
+        msg.note(String::from("borrows are exclusive"));
+        msg.span_suggestion(cm.span_substr(&foo, "&mut vec", 1).unwrap(),
+                            String::from("consider cloning"),
+                            String::from("vec.clone()"),
+                            Applicability::MaybeIncorrect);
+        msg
+    }
+
+    fn replay(loader: MemLoader, json: &str) -> Vec<CompilerMessage> {
+        let cm = Rc::new(CodeMap::with_file_loader(Box::new(loader)));
+        to_compiler_messages(&cm, &parse_diagnostics(json))
+    }
+
+    #[test]
+    fn renders_the_same_from_the_file() {
+        let msg = test_message();
+        let loader = MemLoader::default();
+        loader.insert("foo.rs", FOO_RS);
+
+        let replayed = replay(loader, &render_json(&msg));
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(make_string(&render_succinct(&replayed[0])),
+                   make_string(&render_succinct(&msg)));
+        assert_eq!(replayed[0].cm.get_filemap("foo.rs").src.as_ref().unwrap()[..], *FOO_RS);
+    }
+
+    #[test]
+    fn renders_the_same_from_quoted_lines() {
+        let msg = test_message();
+        // The file has changed since, so it's pieced together from the quoted
+        // lines, which render the same
+        let loader = MemLoader::default();
+        loader.insert("foo.rs", "fn main() {}\n");
+
+        let replayed = replay(loader, &render_json(&msg));
+        // except for the line in between, which nothing quoted
+        assert_eq!(make_string(&render_succinct(&replayed[0])),
+                   make_string(&render_succinct(&msg)).replace("3 |>    // unrelated", "3 |>"));
+        assert_eq!(replayed[0].cm.get_filemap("foo.rs").src.as_ref().unwrap()[..],
+                   *"\n    let first = &mut vec;\n\n    let second = &mut vec;\n");
+    }
+
+    #[test]
+    fn reads_cargo_messages() {
+        let msg = test_message();
+        let input = format!("   Compiling foo v0.1.0\n\
+                             {{\"reason\":\"compiler-artifact\"}}\n\
+                             {{\"reason\":\"compiler-message\",\"message\":{}}}\n\
+                             {}\n",
+                            render_json(&msg),
+                            render_json(&msg));
+
+        let replayed = replay(MemLoader::default(), &input);
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[1].error_code, Some(String::from("E0499")));
+        assert_eq!(replayed[1].suggestions[0].applicability, Applicability::MaybeIncorrect);
+    }
+}
@@ -0,0 +1,615 @@
+//! A SARIF emitter, writing messages as a log in the Static Analysis Results
+//! Interchange Format 2.1.0, which code scanning services and editors read.
+//!
+//! Unlike the other emitters, a SARIF log is a single document, so the
+//! `SarifEmitter` keeps the messages until it's finished.
+
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use serde_json;
+
+use styled_buffer::Level;
+use compiler_message::CompilerMessage;
+use styled_emit::Emitter;
+use codemap::{CodeMap, FileMap, Span, DUMMY_SP};
+
+pub const SARIF_VERSION: &str = "2.1.0";
+pub const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SarifLog {
+    #[serde(rename = "$schema")]
+    pub schema: String,
+    pub version: String,
+    pub runs: Vec<Run>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Run {
+    pub tool: Tool,
+    /// Every file of the codemaps of the messages, whether or not a message
+    /// points into it, which locations refer to by index.
+    pub artifacts: Vec<Artifact>,
+    pub results: Vec<SarifResult>,
+    /// How columns are counted, "unicodeCodePoints" as in the other
+    /// emitters, rather than the default of UTF-16 code units.
+    pub column_kind: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tool {
+    pub driver: ToolComponent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolComponent {
+    pub name: String,
+    /// One rule per error code, which results refer to by index.
+    pub rules: Vec<ReportingDescriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReportingDescriptor {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Artifact {
+    pub location: ArtifactLocation,
+    /// The length of the file in bytes.
+    pub length: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArtifactLocation {
+    pub uri: String,
+    /// The index of the file in the artifacts of the run.
+    pub index: usize,
+}
+
+/// A message, named so as not to shadow `Result`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SarifResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_index: Option<usize>,
+    /// "error", "warning", "note" or "none".
+    pub level: String,
+    pub message: Message,
+    /// The primary spans, with their labels as the messages.
+    pub locations: Vec<Location>,
+    /// The other labelled spans, each with its label as the message.
+    pub related_locations: Vec<Location>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Location {
+    /// Set on related locations, which must be told apart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    pub physical_location: PhysicalLocation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PhysicalLocation {
+    pub artifact_location: ArtifactLocation,
+    pub region: Region,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Region {
+    /// 1-based.
+    pub start_line: usize,
+    /// 1-based, character offset.
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    /// Byte offsets within the file, not the codemap.
+    pub byte_offset: u32,
+    pub byte_length: u32,
+}
+
+/// Builds the run, adding the codes of the messages as they come.
+struct RunBuilder {
+    codemaps: Vec<Rc<CodeMap>>,
+    files: Vec<Rc<FileMap>>,
+    rules: Vec<ReportingDescriptor>,
+    results: Vec<SarifResult>,
+}
+
+impl SarifLog {
+    /// A log of a single run of `tool_name`, with a result per message.
+    pub fn from_compiler_messages(tool_name: &str, msgs: &[CompilerMessage]) -> SarifLog {
+        let mut run = RunBuilder {
+            codemaps: vec![],
+            files: vec![],
+            rules: vec![],
+            results: vec![],
+        };
+        for msg in msgs {
+            run.add_message(msg);
+        }
+
+        let artifacts = run.files
+            .iter()
+            .enumerate()
+            .map(|(index, fm)| {
+                Artifact {
+                    location: ArtifactLocation {
+                        uri: to_uri(&fm.name),
+                        index: index,
+                    },
+                    length: (fm.end_pos - fm.start_pos).0,
+                }
+            })
+            .collect();
+
+        SarifLog {
+            schema: String::from(SARIF_SCHEMA),
+            version: String::from(SARIF_VERSION),
+            runs: vec![Run {
+                           tool: Tool {
+                               driver: ToolComponent {
+                                   name: tool_name.to_owned(),
+                                   rules: run.rules,
+                               },
+                           },
+                           artifacts: artifacts,
+                           results: run.results,
+                           column_kind: String::from("unicodeCodePoints"),
+                       }],
+        }
+    }
+}
+
+impl RunBuilder {
+    fn add_message(&mut self, msg: &CompilerMessage) {
+        if !self.codemaps.iter().any(|cm| Rc::ptr_eq(cm, &msg.cm)) {
+            self.codemaps.push(msg.cm.clone());
+            self.files.extend(msg.cm.files.borrow().iter().cloned());
+        }
+
+        let rule_index = msg.error_code.as_ref().map(|code| {
+            match self.rules.iter().position(|rule| rule.id == *code) {
+                Some(index) => index,
+                None => {
+                    self.rules.push(ReportingDescriptor { id: code.clone() });
+                    self.rules.len() - 1
+                }
+            }
+        });
+
+        let mut locations = vec![];
+        let mut related_locations = vec![];
+        let has_primary_label = msg.span_labels.iter().any(|sl| sl.is_primary);
+        if !has_primary_label && !msg.primary_span.source_equal(&DUMMY_SP) {
+            locations.push(Location {
+                id: None,
+                physical_location: self.physical_location(msg.primary_span, &msg.cm),
+                message: None,
+            });
+        }
+        for span_label in &msg.span_labels {
+            let physical_location = self.physical_location(span_label.span, &msg.cm);
+            if span_label.is_primary {
+                locations.push(Location {
+                    id: None,
+                    physical_location: physical_location,
+                    message: span_label.label.clone().map(|text| Message { text: text }),
+                });
+            } else {
+                related_locations.push(Location {
+                    id: Some(related_locations.len()),
+                    physical_location: physical_location,
+                    message: span_label.label.clone().map(|text| Message { text: text }),
+                });
+            }
+        }
+
+        self.results.push(SarifResult {
+            rule_id: msg.error_code.clone(),
+            rule_index: rule_index,
+            level: sarif_level(msg.level).to_owned(),
+            message: Message { text: msg.primary_msg.clone() },
+            locations: locations,
+            related_locations: related_locations,
+        });
+    }
+
+    fn physical_location(&mut self, span: Span, cm: &CodeMap) -> PhysicalLocation {
+        let start = cm.lookup_char_pos(span.lo);
+        let end = cm.lookup_char_pos(span.hi);
+        let fm = start.file.clone();
+
+        let index = match self.files.iter().position(|file| Rc::ptr_eq(file, &fm)) {
+            Some(index) => index,
+            None => {
+                self.files.push(fm.clone());
+                self.files.len() - 1
+            }
+        };
+
+        PhysicalLocation {
+            artifact_location: ArtifactLocation {
+                uri: to_uri(&fm.name),
+                index: index,
+            },
+            region: Region {
+                start_line: start.line,
+                start_column: start.col.0 + 1,
+                end_line: end.line,
+                end_column: end.col.0 + 1,
+                byte_offset: (span.lo - fm.start_pos).0,
+                byte_length: (span.hi - span.lo).0,
+            },
+        }
+    }
+}
+
+fn sarif_level(level: Level) -> &'static str {
+    match level {
+        Level::Bug | Level::Fatal | Level::PhaseFatal | Level::Error => "error",
+        Level::Warning => "warning",
+        Level::Note | Level::Help => "note",
+        Level::FailureNote | Level::Cancelled => "none",
+    }
+}
+
+/// Turns a file name into a relative or `file:` URI, percent-encoding
+/// anything but the unreserved characters and separators.
+pub fn to_uri(name: &str) -> String {
+    let path = name.replace('\\', "/");
+    let mut uri = String::new();
+    if path.starts_with('/') {
+        uri.push_str("file://");
+    } else if path.as_bytes().get(1) == Some(&b':') {
+        // A Windows path with a drive letter
+        uri.push_str("file:///");
+    }
+    for (i, byte) in path.bytes().enumerate() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            b':' if i == 1 && uri.starts_with("file:") => uri.push(':'),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Serializes the messages as a SARIF log of a run of `tool_name`.
+pub fn render_sarif(tool_name: &str, msgs: &[CompilerMessage]) -> String {
+    serde_json::to_string_pretty(&SarifLog::from_compiler_messages(tool_name, msgs))
+        .expect("SARIF logs are always serializable")
+}
+
+pub struct SarifEmitter {
+    dst: Box<dyn Write + Send>,
+    tool_name: String,
+    msgs: Vec<CompilerMessage>,
+}
+
+impl SarifEmitter {
+    pub fn stdout(tool_name: &str) -> SarifEmitter {
+        SarifEmitter::new(Box::new(io::stdout()), tool_name)
+    }
+
+    pub fn new(dst: Box<dyn Write + Send>, tool_name: &str) -> SarifEmitter {
+        SarifEmitter {
+            dst: dst,
+            tool_name: tool_name.to_owned(),
+            msgs: vec![],
+        }
+    }
+
+    /// Writes the log of every message emitted so far.
+    pub fn finish(mut self) -> io::Result<()> {
+        writeln!(self.dst, "{}", render_sarif(&self.tool_name, &self.msgs))?;
+        self.dst.flush()
+    }
+}
+
+impl Emitter for SarifEmitter {
+    /// Keeps the message for the log written by `finish`.
+    fn emit(&mut self, msg: &CompilerMessage) -> io::Result<()> {
+        self.msgs.push(msg.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonschema::JSONSchema;
+    use serde_json::Value;
+
+    use test_utils::{test_messages, SharedBuf};
+
+    /// The definitions of the SARIF 2.1.0 schema for the properties written
+    /// here, with the same constraints, down to the `additionalProperties`
+    /// which catch misspelt names.
+    const SCHEMA: &str = r##"{
+  "$schema": "http://json-schema.org/draft-07/schema#",
+  "type": "object",
+  "$ref": "#/definitions/sarifLog",
+  "definitions": {
+    "sarifLog": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "$schema": { "type": "string", "format": "uri" },
+        "version": { "enum": ["2.1.0"] },
+        "runs": { "type": ["array", "null"], "items": { "$ref": "#/definitions/run" } }
+      },
+      "required": ["version", "runs"]
+    },
+    "run": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "tool": { "$ref": "#/definitions/tool" },
+        "artifacts": {
+          "type": "array",
+          "minItems": 0,
+          "uniqueItems": true,
+          "items": { "$ref": "#/definitions/artifact" }
+        },
+        "results": { "type": "array", "items": { "$ref": "#/definitions/result" } },
+        "columnKind": { "enum": ["utf16CodeUnits", "unicodeCodePoints"] }
+      },
+      "required": ["tool"]
+    },
+    "tool": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": { "driver": { "$ref": "#/definitions/toolComponent" } },
+      "required": ["driver"]
+    },
+    "toolComponent": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "name": { "type": "string" },
+        "rules": {
+          "type": "array",
+          "uniqueItems": true,
+          "items": { "$ref": "#/definitions/reportingDescriptor" }
+        }
+      },
+      "required": ["name"]
+    },
+    "reportingDescriptor": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": { "id": { "type": "string" } },
+      "required": ["id"]
+    },
+    "artifact": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "location": { "$ref": "#/definitions/artifactLocation" },
+        "length": { "type": "integer", "minimum": -1 }
+      }
+    },
+    "artifactLocation": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "uri": { "type": "string", "format": "uri-reference" },
+        "index": { "type": "integer", "minimum": -1 }
+      }
+    },
+    "result": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "ruleId": { "type": "string" },
+        "ruleIndex": { "type": "integer", "minimum": -1 },
+        "level": { "enum": ["none", "note", "warning", "error"] },
+        "message": { "$ref": "#/definitions/message" },
+        "locations": { "type": "array", "items": { "$ref": "#/definitions/location" } },
+        "relatedLocations": {
+          "type": "array",
+          "uniqueItems": true,
+          "items": { "$ref": "#/definitions/location" }
+        }
+      },
+      "required": ["message"]
+    },
+    "message": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": { "text": { "type": "string" } },
+      "anyOf": [{ "required": ["text"] }, { "required": ["id"] }]
+    },
+    "location": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "id": { "type": "integer", "minimum": -1 },
+        "physicalLocation": { "$ref": "#/definitions/physicalLocation" },
+        "message": { "$ref": "#/definitions/message" }
+      }
+    },
+    "physicalLocation": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "artifactLocation": { "$ref": "#/definitions/artifactLocation" },
+        "region": { "$ref": "#/definitions/region" }
+      },
+      "anyOf": [{ "required": ["address"] }, { "required": ["artifactLocation"] }]
+    },
+    "region": {
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {
+        "startLine": { "type": "integer", "minimum": 1 },
+        "startColumn": { "type": "integer", "minimum": 1 },
+        "endLine": { "type": "integer", "minimum": 1 },
+        "endColumn": { "type": "integer", "minimum": 1 },
+        "byteOffset": { "type": "integer", "minimum": -1 },
+        "byteLength": { "type": "integer", "minimum": 0 }
+      }
+    }
+  }
+}"##;
+
+    fn validate(log: &Value) {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        let schema = JSONSchema::compile(&schema).unwrap();
+        let errors: Vec<String> = match schema.validate(log) {
+            Ok(()) => return,
+            Err(errors) => errors.map(|err| format!("{} at {}", err, err.instance_path)).collect(),
+        };
+        panic!("invalid SARIF log:\n{}", errors.join("\n"));
+    }
+
+    /// Checks what the schema can't: that every index into the artifacts and
+    /// rules of a run points at the entry with the same URI or id.
+    fn check_indexes(log: &Value) {
+        for run in log["runs"].as_array().unwrap() {
+            let no_entries = vec![];
+            let artifacts = run["artifacts"].as_array().unwrap_or(&no_entries);
+            let rules = run["tool"]["driver"]["rules"].as_array().unwrap_or(&no_entries);
+            for (i, artifact) in artifacts.iter().enumerate() {
+                assert_eq!(artifact["location"]["index"], json!(i));
+            }
+
+            for result in run["results"].as_array().unwrap() {
+                if let Some(index) = result["ruleIndex"].as_u64() {
+                    assert_eq!(rules[index as usize]["id"], result["ruleId"]);
+                }
+                let locations = result["locations"]
+                    .as_array()
+                    .into_iter()
+                    .chain(result["relatedLocations"].as_array())
+                    .flat_map(|locations| locations.iter());
+                for location in locations {
+                    let artifact_location = &location["physicalLocation"]["artifactLocation"];
+                    if let Some(index) = artifact_location["index"].as_u64() {
+                        assert_eq!(artifacts[index as usize]["location"]["uri"],
+                                   artifact_location["uri"]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sarif_shape() {
+        let log: Value = serde_json::from_str(&render_sarif("rustc", &test_messages())).unwrap();
+        check_indexes(&log);
+
+        assert_eq!(log["version"], "2.1.0");
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["name"], "rustc");
+        assert_eq!(run["tool"]["driver"]["rules"], json!([{ "id": "E0369" }]));
+        // Every file of the codemap is listed, even those no message points at
+        assert_eq!(run["artifacts"],
+                   json!([{ "location": { "uri": "%3Canon%3E", "index": 0 }, "length": 12 },
+                          { "location": { "uri": "src/%3Cf%C3%B6%20o%3E.rs", "index": 1 },
+                            "length": 47 },
+                          { "location": { "uri": "untitled%3Abar", "index": 2 },
+                            "length": 12 }]));
+
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 5);
+        let error = &results[0];
+        assert_eq!(error["ruleId"], "E0369");
+        assert_eq!(error["ruleIndex"], 0);
+        assert_eq!(error["level"], "error");
+        assert_eq!(error["message"]["text"], "can't compare `&str` with `<T>`");
+        assert_eq!(error["locations"],
+                   json!([{
+                       "physicalLocation": {
+                           "artifactLocation": { "uri": "src/%3Cf%C3%B6%20o%3E.rs", "index": 1 },
+                           "region": {
+                               "startLine": 2,
+                               "startColumn": 17,
+                               "endLine": 2,
+                               "endColumn": 18,
+                               "byteOffset": 33,
+                               "byteLength": 1
+                           }
+                       },
+                       "message": { "text": "<T> here" }
+                   }]));
+        let related = error["relatedLocations"].as_array().unwrap();
+        assert_eq!(related.len(), 2);
+        assert_eq!(related[0]["id"], 0);
+        assert_eq!(related[0]["message"]["text"], "&str");
+        assert_eq!(related[0]["physicalLocation"]["region"]["startColumn"], 13);
+        assert_eq!(related[0]["physicalLocation"]["region"]["byteLength"], 6);
+        assert_eq!(related[1]["id"], 1);
+        assert_eq!(related[1]["message"], Value::Null);
+        assert_eq!(related[1]["physicalLocation"]["artifactLocation"]["index"], 2);
+
+        assert_eq!(results[1]["level"], "warning");
+        assert_eq!(results[1]["ruleId"], Value::Null);
+        assert_eq!(results[1]["locations"][0]["physicalLocation"]["region"]["startLine"], 1);
+        assert_eq!(results[1]["locations"][0]["message"], Value::Null);
+        assert_eq!(results[2]["ruleIndex"], 0);
+        assert_eq!(results[3]["level"], "note");
+        assert_eq!(results[3]["locations"], json!([]));
+        assert_eq!(results[4]["level"], "note");
+        assert_eq!(results[4]["locations"][0]["physicalLocation"]["region"]["byteLength"], 4);
+    }
+
+    #[test]
+    fn sarif_schema() {
+        validate(&serde_json::from_str(&render_sarif("rustc", &test_messages())).unwrap());
+        validate(&serde_json::from_str(&render_sarif("rustc", &[])).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid SARIF log")]
+    fn sarif_schema_rejects_misspelt_names() {
+        validate(&json!({
+            "version": "2.1.0",
+            "runs": [{ "tool": { "driver": { "name": "rustc" } }, "column_kind": "none" }]
+        }));
+    }
+
+    #[test]
+    fn sarif_uri() {
+        assert_eq!(to_uri("src/main.rs"), "src/main.rs");
+        assert_eq!(to_uri("/home/me/a b.rs"), "file:///home/me/a%20b.rs");
+        assert_eq!(to_uri("C:\\src\\main.rs"), "file:///C:/src/main.rs");
+        assert_eq!(to_uri("<anon>"), "%3Canon%3E");
+    }
+
+    #[test]
+    fn sarif_emitter() {
+        let buf = SharedBuf::default();
+        let mut emitter = SarifEmitter::new(Box::new(buf.clone()), "rustc");
+        for msg in &test_messages() {
+            emitter.emit(msg).unwrap();
+        }
+        assert!(buf.text().is_empty());
+        emitter.finish().unwrap();
+
+        let output = buf.text();
+        let log: Value = serde_json::from_str(&output).unwrap();
+        validate(&log);
+        check_indexes(&log);
+        assert_eq!(log["runs"][0]["results"].as_array().unwrap().len(), 5);
+    }
+}
@@ -1,7 +1,7 @@
 use term;
 use std::fmt;
 
-#[derive(Copy, PartialEq, Clone, Debug)]
+#[derive(Copy, PartialEq, Eq, Hash, Clone, Debug)]
 pub enum Level {
     Bug,
     Fatal,
@@ -12,6 +12,9 @@ pub enum Level {
     Warning,
     Note,
     Help,
+    /// Printed after the other messages, without a level, eg) to point to the
+    /// explanations of the error codes.
+    FailureNote,
     Cancelled,
 }
 
@@ -30,6 +33,7 @@ pub enum Style {
     OldSchoolNote,
     NoStyle,
     ErrorCode,
+    Addition,
     Level(Level),
 }
 
@@ -46,29 +50,81 @@ pub struct StyledBuffer {
 }
 
 impl Level {
+    /// The name of the level in the names of styles, in themes and CSS
+    /// classes. The errors of every kind share a name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Bug | Level::Fatal | Level::PhaseFatal | Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Note => "note",
+            Level::Help => "help",
+            Level::FailureNote => "failure-note",
+            Level::Cancelled => "cancelled",
+        }
+    }
+
     pub fn color(self) -> term::color::Color {
         match self {
             Level::Bug | Level::Fatal | Level::PhaseFatal | Level::Error => term::color::BRIGHT_RED,
             Level::Warning => term::color::YELLOW,
             Level::Note => term::color::BRIGHT_GREEN,
             Level::Help => term::color::BRIGHT_CYAN,
+            Level::FailureNote => term::color::BRIGHT_WHITE,
             Level::Cancelled => unreachable!(),
         }
     }
+}
 
-    pub fn to_string(self) -> String {
-        let output = match self {
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let output = match *self {
             Level::Bug => "error: internal compiler error",
             Level::Fatal | Level::PhaseFatal | Level::Error => "error",
             Level::Warning => "warning",
             Level::Note => "note",
             Level::Help => "help",
+            Level::FailureNote => "failure-note",
             Level::Cancelled => panic!("Shouldn't call on cancelled error"),
         };
-        String::from(output)
+        f.write_str(output)
     }
 }
 
+impl Style {
+    /// The name of the style in themes and CSS classes, eg) `line-number`.
+    /// Every `Level` style is named `level`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Style::HeaderMsg => "header-msg",
+            Style::FileNameStyle => "file-name",
+            Style::LineAndColumn => "line-and-column",
+            Style::LineNumber => "line-number",
+            Style::Quotation => "quotation",
+            Style::UnderlinePrimary => "underline-primary",
+            Style::UnderlineSecondary => "underline-secondary",
+            Style::LabelPrimary => "label-primary",
+            Style::LabelSecondary => "label-secondary",
+            Style::OldSchoolNoteText => "old-school-note-text",
+            Style::OldSchoolNote => "old-school-note",
+            Style::NoStyle => "no-style",
+            Style::ErrorCode => "error-code",
+            Style::Addition => "addition",
+            Style::Level(_) => "level",
+        }
+    }
+}
+
+/// Flattens rendered lines into plain text, dropping their styles.
+pub fn make_string(lines: &[Vec<StyledString>]) -> String {
+    lines.iter()
+        .flat_map(|rl| {
+            rl.iter()
+                .map(|s| &s.text[..])
+                .chain(Some("\n"))
+        })
+        .collect()
+}
+
 impl StyledBuffer {
     pub fn new() -> StyledBuffer {
         StyledBuffer {
@@ -127,15 +183,9 @@ impl StyledBuffer {
             self.text[line][col] = chr;
             self.styles[line][col] = style;
         } else {
-            let mut i = self.text[line].len();
-            while i < col {
-                let s = match self.text[0].get(i) {
-                    Some(&'\t') => '\t',
-                    _ => ' ',
-                };
-                self.text[line].push(s);
+            while self.text[line].len() < col {
+                self.text[line].push(' ');
                 self.styles[line].push(Style::NoStyle);
-                i += 1;
             }
             self.text[line].push(chr);
             self.styles[line].push(style);
@@ -161,7 +211,7 @@ impl StyledBuffer {
         let string_len = string.len();
 
         // Push the old content over to make room for new content
-        for i in 0..string_len {
+        for _ in 0..string_len {
             self.styles[line].insert(0, Style::NoStyle);
             self.text[line].insert(0, ' ');
         }
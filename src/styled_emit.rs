@@ -1,74 +1,153 @@
+use std::borrow::Cow;
 use std::io::prelude::*;
-use std::io;
-use std::fmt;
+use std::io::{self, IsTerminal};
+use std::ffi::OsString;
+use std::sync::OnceLock;
+use std::{env, fmt};
 
 use term;
 use styled_buffer::*;
+use compiler_message::CompilerMessage;
+use render_succinct::{render_succinct_with_config, RenderConfig};
+use render_short::render_short;
+use theme::{Color, ColorDepth, TextStyle, Theme};
 
-use compiler_message::*;
+/// Something which diagnostics can be sent to.
+pub trait Emitter {
+    fn emit(&mut self, msg: &CompilerMessage) -> io::Result<()>;
+}
+
+/// Whether to color the output.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ColorConfig {
+    /// Color terminals, unless `NO_COLOR` is set. `CLICOLOR_FORCE` colors
+    /// other outputs too.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorConfig {
+    /// Decides whether to color an output, which may or may not be a terminal.
+    pub fn use_color(self, is_terminal: bool) -> bool {
+        self.use_color_with_env(is_terminal, |var| env::var_os(var))
+    }
+
+    fn use_color_with_env<F>(self, is_terminal: bool, env_var: F) -> bool
+        where F: Fn(&str) -> Option<OsString>
+    {
+        // Both conventions only count the variable as set when it isn't
+        // empty, and CLICOLOR_FORCE=0 leaves it up to the terminal
+        let no_color = env_var("NO_COLOR").is_some_and(|val| !val.is_empty());
+        let force = env_var("CLICOLOR_FORCE").is_some_and(|val| !val.is_empty() && val != "0");
+        match self {
+            ColorConfig::Always => true,
+            ColorConfig::Never => false,
+            ColorConfig::Auto => !no_color && (force || is_terminal),
+        }
+    }
+}
 
 pub enum Destination {
     Terminal(Box<term::StderrTerminal>),
-    Raw(Box<Write + Send>),
+    StdoutTerminal(Box<term::StdoutTerminal>),
+    /// Colors the output with ANSI escapes, for when there is no terminfo
+    /// entry to go by but color was asked for anyway.
+    Ansi(AnsiWriter),
+    Raw(Box<dyn Write + Send>),
 }
 
 impl Destination {
-    pub fn from_stderr() -> Destination {
+    pub fn from_stderr(color: ColorConfig) -> Destination {
+        if !color.use_color(io::stderr().is_terminal()) {
+            return Destination::Raw(Box::new(io::stderr()));
+        }
         match term::stderr() {
             Some(t) => Destination::Terminal(t),
-            None => Destination::Raw(Box::new(io::stderr())),
+            None => Destination::Ansi(AnsiWriter::new(Box::new(io::stderr()))),
         }
     }
 
+    pub fn from_stdout(color: ColorConfig) -> Destination {
+        if !color.use_color(io::stdout().is_terminal()) {
+            return Destination::Raw(Box::new(io::stdout()));
+        }
+        match term::stdout() {
+            Some(t) => Destination::StdoutTerminal(t),
+            None => Destination::Ansi(AnsiWriter::new(Box::new(io::stdout()))),
+        }
+    }
+
+    /// Writes to `dst`, which is never taken to be a terminal.
+    pub fn from_writer(dst: Box<dyn Write + Send>, color: ColorConfig) -> Destination {
+        if color.use_color(false) {
+            Destination::Ansi(AnsiWriter::new(dst))
+        } else {
+            Destination::Raw(dst)
+        }
+    }
+
+    /// Styles what's written next as the default theme has it.
     pub fn apply_style(&mut self, lvl: Level, style: Style) -> io::Result<()> {
-        match style {
-            Style::FileNameStyle | Style::LineAndColumn => {}
-            Style::LineNumber => {
-                try!(self.start_attr(term::Attr::Bold));
-                try!(self.start_attr(term::Attr::ForegroundColor(term::color::BRIGHT_BLUE)));
-            }
-            Style::ErrorCode => {
-                try!(self.start_attr(term::Attr::Bold));
-                try!(self.start_attr(term::Attr::ForegroundColor(term::color::BRIGHT_MAGENTA)));
-            }
-            Style::Quotation => {}
-            Style::OldSchoolNote => {
-                try!(self.start_attr(term::Attr::Bold));
-                try!(self.start_attr(term::Attr::ForegroundColor(term::color::BRIGHT_GREEN)));
-            }
-            Style::OldSchoolNoteText | Style::HeaderMsg => {
-                try!(self.start_attr(term::Attr::Bold));
-            }
-            Style::UnderlinePrimary | Style::LabelPrimary => {
-                try!(self.start_attr(term::Attr::Bold));
-                try!(self.start_attr(term::Attr::ForegroundColor(lvl.color())));
-            }
-            Style::UnderlineSecondary |
-            Style::LabelSecondary => {
-                try!(self.start_attr(term::Attr::Bold));
-                try!(self.start_attr(term::Attr::ForegroundColor(term::color::BRIGHT_BLUE)));
-            }
-            Style::NoStyle => {}
-            Style::Level(Level::Error) => {
-                try!(self.start_attr(term::Attr::Bold));
-                try!(self.start_attr(term::Attr::ForegroundColor(term::color::BRIGHT_RED)));
-            }
-            Style::Level(Level::Warning) => {
-                try!(self.start_attr(term::Attr::Bold));
-                try!(self.start_attr(term::Attr::ForegroundColor(term::color::YELLOW)));
+        self.apply_text_style(&default_theme().style(style, lvl), default_color_depth())
+    }
+
+    /// Styles what's written next, with the colors a terminal of `depth`
+    /// shows.
+    pub fn apply_text_style(&mut self, style: &TextStyle, depth: ColorDepth) -> io::Result<()> {
+        match *self {
+            Destination::Ansi(ref mut w) => return w.text_style(style, depth),
+            Destination::Raw(_) => return Ok(()),
+            _ => {}
+        }
+        if style.bold {
+            self.start_attr(term::Attr::Bold)?;
+        }
+        if style.italic {
+            self.start_attr(term::Attr::Italic(true))?;
+        }
+        if style.underline {
+            self.start_attr(term::Attr::Underline(true))?;
+        }
+        if let Some(fg) = style.fg {
+            self.start_color(fg.with_depth(depth), false)?;
+        }
+        if let Some(bg) = style.bg {
+            self.start_color(bg.with_depth(depth), true)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the 16 colors through terminfo, and the others, which it has no
+    /// way to, with their escapes.
+    fn start_color(&mut self, color: Color, background: bool) -> io::Result<()> {
+        match color {
+            Color::Ansi(index) if background => {
+                self.start_attr(term::Attr::BackgroundColor(index as term::color::Color))
             }
-            Style::Level(Level::Note) => {
-                try!(self.start_attr(term::Attr::Bold));
+            Color::Ansi(index) => {
+                self.start_attr(term::Attr::ForegroundColor(index as term::color::Color))
             }
-            Style::Level(_) => {}
+            _ => write!(self, "{}", color.ansi_escape(background)),
         }
-        Ok(())
     }
 
     pub fn start_attr(&mut self, attr: term::Attr) -> io::Result<()> {
         match *self {
+            // Leave out what the terminal can't show, rather than failing
             Destination::Terminal(ref mut t) => {
-                try!(t.attr(attr));
+                if t.supports_attr(attr) {
+                    t.attr(attr)?;
+                }
+            }
+            Destination::StdoutTerminal(ref mut t) => {
+                if t.supports_attr(attr) {
+                    t.attr(attr)?;
+                }
+            }
+            Destination::Ansi(ref mut w) => {
+                w.attr(attr)?;
             }
             Destination::Raw(_) => {}
         }
@@ -78,7 +157,13 @@ impl Destination {
     pub fn reset_attrs(&mut self) -> io::Result<()> {
         match *self {
             Destination::Terminal(ref mut t) => {
-                try!(t.reset());
+                t.reset()?;
+            }
+            Destination::StdoutTerminal(ref mut t) => {
+                t.reset()?;
+            }
+            Destination::Ansi(ref mut w) => {
+                w.reset()?;
             }
             Destination::Raw(_) => {}
         }
@@ -92,7 +177,7 @@ impl Destination {
                               -> io::Result<()> {
         match *self {
             Destination::Terminal(ref mut t) => {
-                try!(t.attr(color));
+                t.attr(color)?;
                 // If `msg` ends in a newline, we need to reset the color before
                 // the newline. We're making the assumption that we end up writing
                 // to a `LineBufferedWriter`, which means that emitting the reset
@@ -106,16 +191,36 @@ impl Destination {
                 // once, which still leaves the opportunity for interleaved output
                 // to be miscolored. We assume this is rare enough that we don't
                 // have to worry about it.
-                try!(t.write_fmt(args));
-                try!(t.reset());
+                t.write_fmt(args)?;
+                t.reset()?;
                 if print_newline_at_end {
                     t.write_all(b"\n")
                 } else {
                     Ok(())
                 }
             }
+            Destination::StdoutTerminal(ref mut t) => {
+                t.attr(color)?;
+                t.write_fmt(args)?;
+                t.reset()?;
+                if print_newline_at_end {
+                    t.write_all(b"\n")
+                } else {
+                    Ok(())
+                }
+            }
+            Destination::Ansi(ref mut w) => {
+                w.attr(color)?;
+                w.write_fmt(args)?;
+                w.reset()?;
+                if print_newline_at_end {
+                    w.write_all(b"\n")
+                } else {
+                    Ok(())
+                }
+            }
             Destination::Raw(ref mut w) => {
-                try!(w.write_fmt(args));
+                w.write_fmt(args)?;
                 if print_newline_at_end {
                     w.write_all(b"\n")
                 } else {
@@ -130,27 +235,330 @@ impl Write for Destination {
     fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
         match *self {
             Destination::Terminal(ref mut t) => t.write(bytes),
+            Destination::StdoutTerminal(ref mut t) => t.write(bytes),
+            Destination::Ansi(ref mut w) => w.write(bytes),
             Destination::Raw(ref mut w) => w.write(bytes),
         }
     }
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Destination::Terminal(ref mut t) => t.flush(),
+            Destination::StdoutTerminal(ref mut t) => t.flush(),
+            Destination::Ansi(ref mut w) => w.flush(),
             Destination::Raw(ref mut w) => w.flush(),
         }
     }
 }
 
+/// Writes the attributes used by `Destination` as ANSI escape sequences.
+pub struct AnsiWriter {
+    dst: Box<dyn Write + Send>,
+    // Whether an attribute was set since the last reset, so that unstyled
+    // text isn't followed by needless resets
+    styled: bool,
+}
+
+impl AnsiWriter {
+    pub fn new(dst: Box<dyn Write + Send>) -> AnsiWriter {
+        AnsiWriter {
+            dst: dst,
+            styled: false,
+        }
+    }
+
+    pub fn attr(&mut self, attr: term::Attr) -> io::Result<()> {
+        match attr {
+            term::Attr::Bold => write!(self.dst, "\x1b[1m")?,
+            term::Attr::ForegroundColor(color) if color < 8 => {
+                write!(self.dst, "\x1b[3{}m", color)?
+            }
+            term::Attr::ForegroundColor(color) if color < 16 => {
+                write!(self.dst, "\x1b[9{}m", color - 8)?
+            }
+            term::Attr::ForegroundColor(color) => write!(self.dst, "\x1b[38;5;{}m", color)?,
+            // Nothing else is used for diagnostics
+            _ => return Ok(()),
+        }
+        self.styled = true;
+        Ok(())
+    }
+
+    /// Writes the escapes of `style` on a terminal of `depth`.
+    pub fn text_style(&mut self, style: &TextStyle, depth: ColorDepth) -> io::Result<()> {
+        let escape = style.ansi_escape(depth);
+        if !escape.is_empty() {
+            self.dst.write_all(escape.as_bytes())?;
+            self.styled = true;
+        }
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> io::Result<()> {
+        if self.styled {
+            self.styled = false;
+            write!(self.dst, "\x1b[0m")?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AnsiWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.dst.write(bytes)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.dst.flush()
+    }
+}
+
+/// Writes already rendered lines to stderr.
 pub fn emit(level: Level, msg: Vec<Vec<StyledString>>) -> io::Result<()> {
-    let mut dst = Destination::from_stderr();
+    let mut dst = Destination::from_stderr(ColorConfig::Auto);
+    emit_to(&mut dst, default_theme(), default_color_depth(), level, &msg)
+}
+
+/// The theme of the writers which aren't given one, only built once.
+fn default_theme() -> &'static Theme {
+    static DEFAULT_THEME: OnceLock<Theme> = OnceLock::new();
+    DEFAULT_THEME.get_or_init(Theme::default)
+}
+
+/// The depth of the terminal for the writers which aren't given one, only
+/// detected once.
+fn default_color_depth() -> ColorDepth {
+    static DEFAULT_COLOR_DEPTH: OnceLock<ColorDepth> = OnceLock::new();
+    *DEFAULT_COLOR_DEPTH.get_or_init(ColorDepth::detect)
+}
 
+fn emit_to(dst: &mut Destination,
+           theme: &Theme,
+           depth: ColorDepth,
+           level: Level,
+           msg: &[Vec<StyledString>])
+           -> io::Result<()> {
     for line in msg {
         for part in line {
-            dst.apply_style(level, part.style);
-            write!(&mut dst, "{}", part.text);
+            dst.apply_text_style(&theme.style(part.style, level), depth)?;
+            write!(dst, "{}", part.text)?;
             dst.reset_attrs()?;
         }
-        write!(&mut dst, "\n");
+        writeln!(dst)?;
+    }
+    dst.flush()
+}
+
+/// Renders messages with `render_succinct` and writes them to a terminal or
+/// any other writer, colored as the `ColorConfig` says.
+pub struct EmitterWriter {
+    dst: Destination,
+    config: RenderConfig,
+    short_message: bool,
+    /// The default theme unless one is set, so that it isn't built again
+    /// for every writer.
+    theme: Cow<'static, Theme>,
+    color_depth: ColorDepth,
+}
+
+impl EmitterWriter {
+    pub fn stderr(color: ColorConfig) -> EmitterWriter {
+        EmitterWriter::from_destination(Destination::from_stderr(color))
+    }
+
+    pub fn stdout(color: ColorConfig) -> EmitterWriter {
+        EmitterWriter::from_destination(Destination::from_stdout(color))
+    }
+
+    /// Writes plain text to `dst`.
+    pub fn new(dst: Box<dyn Write + Send>) -> EmitterWriter {
+        EmitterWriter::from_destination(Destination::Raw(dst))
+    }
+
+    pub fn with_color(dst: Box<dyn Write + Send>, color: ColorConfig) -> EmitterWriter {
+        EmitterWriter::from_destination(Destination::from_writer(dst, color))
+    }
+
+    pub fn from_destination(dst: Destination) -> EmitterWriter {
+        EmitterWriter {
+            dst: dst,
+            config: RenderConfig::default(),
+            short_message: false,
+            theme: Cow::Borrowed(default_theme()),
+            color_depth: default_color_depth(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: RenderConfig) {
+        self.config = config;
+    }
+
+    /// Writes each message as a single line with `render_short` instead.
+    pub fn set_short_message(&mut self, short_message: bool) {
+        self.short_message = short_message;
+    }
+
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = Cow::Owned(theme);
+    }
+
+    /// Overrides the number of colors found from the environment.
+    pub fn set_color_depth(&mut self, color_depth: ColorDepth) {
+        self.color_depth = color_depth;
+    }
+}
+
+impl Emitter for EmitterWriter {
+    fn emit(&mut self, msg: &CompilerMessage) -> io::Result<()> {
+        let lines = if self.short_message {
+            render_short(msg)
+        } else {
+            render_succinct_with_config(msg, &self.config)
+        };
+        emit_to(&mut self.dst, &self.theme, self.color_depth, msg.level, &lines)
+    }
+}
+
+/// Keeps the rendered lines of every message in memory, styles included.
+#[derive(Debug, Default)]
+pub struct CaptureEmitter {
+    pub lines: Vec<Vec<StyledString>>,
+    pub config: RenderConfig,
+}
+
+impl CaptureEmitter {
+    pub fn new() -> CaptureEmitter {
+        CaptureEmitter {
+            lines: vec![],
+            config: RenderConfig::default(),
+        }
+    }
+
+    /// The captured output as plain text.
+    pub fn text(&self) -> String {
+        make_string(&self.lines)
+    }
+}
+
+impl Emitter for CaptureEmitter {
+    fn emit(&mut self, msg: &CompilerMessage) -> io::Result<()> {
+        self.lines.extend(render_succinct_with_config(msg, &self.config));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+
+    use codemap::CodeMap;
+    use render_succinct::render_succinct;
+    use test_utils::{env, SharedBuf};
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_message() -> CompilerMessage {
+        let cm = Rc::new(CodeMap::new());
+        let foo = cm.new_filemap_and_lines("foo.rs", "fn foo() {}\n");
+        let span = cm.span_substr(&foo, "foo", 0).unwrap();
+        let mut msg = CompilerMessage::new(Level::Warning,
+                                           String::from("function is never used"),
+                                           span,
+                                           None,
+                                           cm);
+        msg.span_label(span, None);
+        msg
+    }
+
+    #[test]
+    fn writer_gets_plain_text() {
+        let out = SharedBuf::default();
+        let mut emitter = EmitterWriter::new(Box::new(out.clone()));
+        let msg = test_message();
+        emitter.emit(&msg).unwrap();
+        emitter.emit(&msg).unwrap();
+
+        let out = out.text();
+        let expected = make_string(&render_succinct(&msg));
+        assert_eq!(out, format!("{}{}", expected, expected));
+    }
+
+    #[test]
+    fn write_errors_are_returned() {
+        let mut emitter = EmitterWriter::new(Box::new(Broken));
+        let err = emitter.emit(&test_message()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn color_config() {
+        let auto = ColorConfig::Auto;
+        assert!(auto.use_color_with_env(true, env(&[])));
+        assert!(!auto.use_color_with_env(false, env(&[])));
+        assert!(!auto.use_color_with_env(true, env(&[("NO_COLOR", "1")])));
+        assert!(auto.use_color_with_env(true, env(&[("NO_COLOR", "")])));
+        assert!(auto.use_color_with_env(false, env(&[("CLICOLOR_FORCE", "1")])));
+        assert!(!auto.use_color_with_env(false, env(&[("CLICOLOR_FORCE", "0")])));
+        let both = env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")]);
+        assert!(!auto.use_color_with_env(false, both));
+
+        let always = ColorConfig::Always;
+        assert!(always.use_color_with_env(false, env(&[])));
+        assert!(always.use_color_with_env(false, env(&[("NO_COLOR", "1")])));
+
+        let never = ColorConfig::Never;
+        assert!(!never.use_color_with_env(true, env(&[])));
+        assert!(!never.use_color_with_env(true, env(&[("CLICOLOR_FORCE", "1")])));
+    }
+
+    #[test]
+    fn always_writes_escapes() {
+        let out = SharedBuf::default();
+        let mut emitter = EmitterWriter::with_color(Box::new(out.clone()),
+                                                    ColorConfig::Always);
+        emitter.emit(&test_message()).unwrap();
+
+        let out = out.text();
+        assert_eq!(out,
+                   concat!("\x1b[1m\x1b[33mwarning\x1b[0m",
+                           "\x1b[1m: function is never used\x1b[0m\n",
+                           " \x1b[1m\x1b[94m--> \x1b[0mfoo.rs:1:3\n",
+                           "  \x1b[1m\x1b[94m|>\x1b[0m\n",
+                           "\x1b[1m\x1b[94m1\x1b[0m \x1b[1m\x1b[94m|>\x1b[0m",
+                           "fn \x1b[1m\x1b[33mfoo\x1b[0m() {}\n",
+                           "  \x1b[1m\x1b[94m|>\x1b[0m   \x1b[1m\x1b[33m^^^\x1b[0m\n"));
+    }
+
+    #[test]
+    fn never_writes_plain_text() {
+        let out = SharedBuf::default();
+        let mut emitter = EmitterWriter::with_color(Box::new(out.clone()),
+                                                    ColorConfig::Never);
+        let msg = test_message();
+        emitter.emit(&msg).unwrap();
+
+        let out = out.text();
+        assert!(!out.contains('\x1b'));
+        assert_eq!(out, make_string(&render_succinct(&msg)));
+    }
+
+    #[test]
+    fn capture_keeps_styles() {
+        let mut emitter = CaptureEmitter::new();
+        let msg = test_message();
+        emitter.emit(&msg).unwrap();
+
+        assert_eq!(emitter.text(), make_string(&render_succinct(&msg)));
+        assert_eq!(emitter.lines[0][0].text, "warning");
+        assert_eq!(emitter.lines[0][0].style, Style::Level(Level::Warning));
     }
-    Ok(())
 }
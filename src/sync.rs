@@ -0,0 +1,377 @@
+//! A `CodeMap` which can be shared between threads, for front ends which parse
+//! and check files in parallel.
+//!
+//! Worker threads add files to a `SyncCodeMap`, record expansions in it and
+//! build `PendingMessage`s against the spans it hands out. Those are collected
+//! in a `MessageSink`. Once the workers are done, the map is copied into a
+//! regular `CodeMap` with `to_codemap`, which keeps every position the same,
+//! and the collected messages are turned into `CompilerMessage`s for it, in an
+//! order which doesn't depend on how the threads were scheduled.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+
+use styled_buffer::Level;
+use compiler_message::*;
+use codemap::{self, BytePos, CodeMap, ExpnId, ExpnInfo, FileLoader, FileName, MultiByteChar,
+              Pos, RealFileLoader, Span, NO_EXPANSION};
+
+/// A single source in a `SyncCodeMap`. Unlike a `FileMap`, it can't be
+/// changed once added, so its lines and multi-byte characters are found when
+/// it's created.
+pub struct SyncFileMap {
+    pub name: FileName,
+    /// The complete source code, if it's available
+    pub src: Option<Arc<String>>,
+    /// The start position of this source in the CodeMap
+    pub start_pos: BytePos,
+    /// The end position of this source in the CodeMap
+    pub end_pos: BytePos,
+    /// Locations of lines beginnings, relative to the start of the file
+    pub lines: Vec<BytePos>,
+    /// Locations of multi-byte characters, relative to the start of the file
+    pub multibyte_chars: Vec<MultiByteChar>,
+}
+
+impl fmt::Debug for SyncFileMap {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "SyncFileMap({})", self.name)
+    }
+}
+
+impl SyncFileMap {
+    /// The span between two byte offsets relative to the start of the file.
+    pub fn span(&self, lo: usize, hi: usize) -> Span {
+        assert!(lo <= hi && self.start_pos + BytePos::from_usize(hi) <= self.end_pos);
+        Span {
+            lo: self.start_pos + BytePos::from_usize(lo),
+            hi: self.start_pos + BytePos::from_usize(hi),
+            expn_id: NO_EXPANSION,
+        }
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.src.is_none()
+    }
+}
+
+/// A `CodeMap` which files and expansions can be added to from any thread.
+pub struct SyncCodeMap {
+    files: RwLock<Vec<Arc<SyncFileMap>>>,
+    expansions: RwLock<Vec<ExpnInfo>>,
+    file_loader: Box<dyn FileLoader + Send + Sync>,
+}
+
+impl Default for SyncCodeMap {
+    fn default() -> SyncCodeMap {
+        SyncCodeMap::new()
+    }
+}
+
+impl SyncCodeMap {
+    pub fn new() -> SyncCodeMap {
+        SyncCodeMap::with_file_loader(Box::new(RealFileLoader))
+    }
+
+    pub fn with_file_loader(file_loader: Box<dyn FileLoader + Send + Sync>) -> SyncCodeMap {
+        SyncCodeMap {
+            files: RwLock::new(Vec::new()),
+            expansions: RwLock::new(Vec::new()),
+            file_loader: file_loader,
+        }
+    }
+
+    /// Reads a file through the file loader and adds it.
+    pub fn load_file(&self, path: &Path) -> io::Result<Arc<SyncFileMap>> {
+        let src = self.file_loader.read_file(path)?;
+        Ok(self.new_filemap(path.to_str().unwrap().to_string(), src))
+    }
+
+    /// Adds a file, with its lines and multi-byte characters recorded.
+    pub fn new_filemap(&self, filename: FileName, mut src: String) -> Arc<SyncFileMap> {
+        // Remove utf-8 BOM if any.
+        if src.starts_with("\u{feff}") {
+            src.drain(..3);
+        }
+
+        // Scanning the source is the slow part, so it's done before taking
+        // the lock
+        let (lines, multibyte_chars) = codemap::analyze_source(&src, BytePos(0));
+        let len = src.len();
+        self.push_filemap(filename, Some(Arc::new(src)), len, lines, multibyte_chars)
+    }
+
+    /// Adds a file whose source isn't available, like
+    /// `CodeMap::new_imported_filemap`.
+    pub fn new_imported_filemap(&self,
+                                filename: FileName,
+                                source_len: usize,
+                                file_local_lines: Vec<BytePos>,
+                                file_local_multibyte_chars: Vec<MultiByteChar>)
+                                -> Arc<SyncFileMap> {
+        self.push_filemap(filename,
+                          None,
+                          source_len,
+                          file_local_lines,
+                          file_local_multibyte_chars)
+    }
+
+    fn push_filemap(&self,
+                    filename: FileName,
+                    src: Option<Arc<String>>,
+                    source_len: usize,
+                    lines: Vec<BytePos>,
+                    multibyte_chars: Vec<MultiByteChar>)
+                    -> Arc<SyncFileMap> {
+        let mut files = self.files.write().unwrap();
+        // Leave the same gap between files as `CodeMap` does
+        let start_pos = match files.last() {
+            None => 0,
+            Some(last) => last.end_pos.to_usize() + 1,
+        };
+
+        let filemap = Arc::new(SyncFileMap {
+            name: filename,
+            src: src,
+            start_pos: Pos::from_usize(start_pos),
+            end_pos: Pos::from_usize(start_pos + source_len),
+            lines: lines,
+            multibyte_chars: multibyte_chars,
+        });
+
+        files.push(filemap.clone());
+
+        filemap
+    }
+
+    pub fn record_expansion(&self, expn_info: ExpnInfo) -> ExpnId {
+        let mut expansions = self.expansions.write().unwrap();
+        expansions.push(expn_info);
+        let len = expansions.len();
+        if len > u32::MAX as usize {
+            panic!("too many ExpnInfo's!");
+        }
+        ExpnId::from_u32(len as u32 - 1)
+    }
+
+    /// The files added so far, in the order of their positions.
+    pub fn files(&self) -> Vec<Arc<SyncFileMap>> {
+        self.files.read().unwrap().clone()
+    }
+
+    pub fn get_filemap(&self, filename: &str) -> Arc<SyncFileMap> {
+        for fm in self.files.read().unwrap().iter() {
+            if filename == fm.name {
+                return fm.clone();
+            }
+        }
+        panic!("asking for {} which we don't know about", filename);
+    }
+
+    /// Returns the span of the `n`th (0-based) occurrence of `substring` in
+    /// `file`, like `CodeMap::span_substr`.
+    pub fn span_substr(&self, file: &SyncFileMap, substring: &str, n: usize) -> Option<Span> {
+        file.src
+            .as_ref()
+            .and_then(|src| codemap::find_substr(src, substring, n))
+            .map(|(lo, hi)| file.span(lo, hi))
+    }
+
+    /// Copies the files and expansions added so far into a `CodeMap`, where
+    /// they have the same positions and expansion ids, so spans made against
+    /// this map can be used with it. The `CodeMap` uses the `RealFileLoader`.
+    pub fn to_codemap(&self) -> CodeMap {
+        let cm = CodeMap::new();
+
+        for file in self.files.read().unwrap().iter() {
+            let fm = match file.src {
+                Some(ref src) => {
+                    let fm = cm.new_filemap(file.name.clone(), (**src).clone());
+                    *fm.lines.borrow_mut() =
+                        file.lines.iter().map(|&pos| pos + fm.start_pos).collect();
+                    fm.set_multibyte_chars(file.multibyte_chars
+                        .iter()
+                        .map(|mbc| {
+                            MultiByteChar {
+                                pos: mbc.pos + fm.start_pos,
+                                bytes: mbc.bytes,
+                            }
+                        })
+                        .collect());
+                    fm
+                }
+                None => {
+                    cm.new_imported_filemap(file.name.clone(),
+                                            (file.end_pos - file.start_pos).to_usize(),
+                                            file.lines.clone(),
+                                            file.multibyte_chars.clone())
+                }
+            };
+            assert_eq!(fm.start_pos, file.start_pos);
+        }
+
+        for expn_info in self.expansions.read().unwrap().iter() {
+            cm.record_expansion(expn_info.clone());
+        }
+
+        cm
+    }
+}
+
+/// A `CompilerMessage` without the `CodeMap` it refers to, so it can be built
+/// on any thread, with the same methods.
+pub type PendingMessage = CompilerMessage<()>;
+
+impl CompilerMessage<()> {
+    pub fn new_pending(level: Level,
+                       msg: String,
+                       primary_span: Span,
+                       error_code: Option<String>)
+                       -> PendingMessage {
+        CompilerMessage {
+            level: level,
+            primary_span: primary_span,
+            primary_msg: msg,
+            error_code: error_code,
+            span_labels: vec![],
+            children: vec![],
+            suggestions: vec![],
+            keep_expansion_spans: false,
+            cm: (),
+        }
+    }
+
+    /// Attaches the message to `cm`, which should be the `to_codemap` of the
+    /// `SyncCodeMap` its spans came from.
+    pub fn into_message(self, cm: Rc<CodeMap>) -> CompilerMessage {
+        CompilerMessage {
+            level: self.level,
+            primary_span: self.primary_span,
+            primary_msg: self.primary_msg,
+            span_labels: self.span_labels,
+            children: self.children,
+            suggestions: self.suggestions,
+            error_code: self.error_code,
+            keep_expansion_spans: self.keep_expansion_spans,
+            cm: cm,
+        }
+    }
+}
+
+/// Collects the messages built by worker threads until they can be emitted
+/// from a single thread.
+#[derive(Default)]
+pub struct MessageSink {
+    messages: Mutex<Vec<(usize, PendingMessage)>>,
+}
+
+impl MessageSink {
+    pub fn new() -> MessageSink {
+        MessageSink::default()
+    }
+
+    /// Adds a message. `key` decides the order in which messages from
+    /// different threads come out, eg) the index of the file being checked.
+    /// Messages with the same key stay in the order they were added.
+    pub fn push(&self, key: usize, msg: PendingMessage) {
+        self.messages.lock().unwrap().push((key, msg));
+    }
+
+    /// The messages collected, ordered by key and attached to `cm`.
+    pub fn into_messages(self, cm: &Rc<CodeMap>) -> Vec<CompilerMessage> {
+        let mut messages = self.messages.into_inner().unwrap();
+        messages.sort_by_key(|&(key, _)| key);
+        messages.into_iter()
+            .map(|(_, msg)| msg.into_message(cm.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use codemap::{NameAndSpan, MacroBang, Name};
+    use render_succinct::render_succinct;
+    use styled_buffer::make_string;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn shareable_between_threads() {
+        assert_send_sync::<SyncCodeMap>();
+        assert_send_sync::<SyncFileMap>();
+        assert_send_sync::<MessageSink>();
+        assert_send_sync::<PendingMessage>();
+    }
+
+    #[test]
+    fn parallel_workers() {
+        let cm = Arc::new(SyncCodeMap::new());
+        let sink = Arc::new(MessageSink::new());
+
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let cm = cm.clone();
+                let sink = sink.clone();
+                thread::spawn(move || {
+                    let src = format!("fn f{}() {{\n    let é = {};\n}}\n", i, i);
+                    let fm = cm.new_filemap(format!("f{}.rs", i), src);
+                    let span = cm.span_substr(&fm, "é", 0).unwrap();
+                    let text = format!("unused variable in f{}", i);
+                    let mut msg = PendingMessage::new_pending(Level::Warning, text, span, None);
+                    msg.span_label(span, Some(String::from("never used")))
+                        .span_help(span, String::from("if this is intentional, prefix it with \
+                                                       an underscore"));
+                    sink.push(i, msg);
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let cm = Rc::new(cm.to_codemap());
+        let sink = Arc::try_unwrap(sink).ok().unwrap();
+        let messages = sink.into_messages(&cm);
+        assert_eq!(messages.len(), 8);
+        for (i, msg) in messages.iter().enumerate() {
+            let text = make_string(&render_succinct(msg));
+            assert!(text.starts_with(&format!("warning: unused variable in f{}\n", i)));
+            assert!(text.contains(&format!("--> f{}.rs:2:8", i)));
+            assert!(text.contains("let é = "));
+            assert_eq!(msg.children.len(), 1);
+        }
+    }
+
+    #[test]
+    fn to_codemap_keeps_positions() {
+        let scm = SyncCodeMap::new();
+        let a = scm.new_filemap(String::from("a.rs"), String::from("\u{feff}ünï\ncödé\n"));
+        scm.new_imported_filemap(String::from("b.rs"), 10, vec![BytePos(0)], vec![]);
+        let c = scm.new_filemap(String::from("c.rs"), String::from("foo!()\n"));
+        let call_site = scm.span_substr(&c, "foo!()", 0).unwrap();
+        let expn_id = scm.record_expansion(ExpnInfo {
+            call_site: call_site,
+            callee: NameAndSpan {
+                format: MacroBang(Name(0)),
+                allow_internal_unstable: false,
+                span: None,
+            },
+        });
+
+        let cm = scm.to_codemap();
+        let span = scm.span_substr(&a, "dé", 0).unwrap();
+        assert_eq!(cm.span_to_snippet(span).unwrap(), "dé");
+        let loc = cm.lookup_char_pos(span.lo);
+        assert_eq!((&loc.file.name[..], loc.line, loc.col.to_usize()), ("a.rs", 2, 2));
+        assert!(cm.get_filemap("b.rs").is_imported());
+        assert_eq!(cm.get_filemap("c.rs").start_pos, c.start_pos);
+        cm.with_expn_info(expn_id, |info| assert_eq!(info.unwrap().call_site, call_site));
+    }
+}
@@ -0,0 +1,129 @@
+//! Helpers shared by the tests of several modules.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use codemap::{CodeMap, FileLoader, DUMMY_SP};
+use compiler_message::{Applicability, CompilerMessage};
+use styled_buffer::Level;
+
+/// Messages for the tests of the output formats: an error pointing into two
+/// files, one of them `untitled:bar`, with a note and a suggestion, then a
+/// warning, another error with the same code, a help without a span and a
+/// note. The source has characters taking two UTF-16 code units, and the
+/// names, source and messages have characters which need escaping in URIs
+/// and HTML. A third file, `<anon>`, isn't pointed at.
+pub fn test_messages() -> Vec<CompilerMessage> {
+    let cm = Rc::new(CodeMap::new());
+    cm.new_filemap_and_lines("<anon>", "fn baz() {}\n");
+    let foo = cm.new_filemap_and_lines("src/<fö o>.rs",
+                                       "fn foo() {\n    let 𝔸 = \"😀\" < b && 'c';\n}\n");
+    let bar = cm.new_filemap_and_lines("untitled:bar", "fn bar() {}\n");
+    let span_a = cm.span_substr(&foo, "𝔸", 0).unwrap();
+    let span_str = cm.span_substr(&foo, "\"😀\"", 0).unwrap();
+    let span_lt = cm.span_substr(&foo, "<", 0).unwrap();
+    let span_bar = cm.span_substr(&bar, "bar", 0).unwrap();
+
+    let mut error = CompilerMessage::new(Level::Error,
+                                         String::from("can't compare `&str` with `<T>`"),
+                                         span_lt,
+                                         Some(String::from("E0369")),
+                                         cm.clone());
+    error.span_label(span_lt, Some(String::from("<T> here")));
+    error.span_label(span_str, Some(String::from("&str")));
+    error.span_label(span_bar, None);
+    error.note(String::from("`<` needs `PartialOrd`"));
+    error.span_suggestion(span_lt,
+                          String::from("try comparing for equality"),
+                          String::from("=="),
+                          Applicability::MaybeIncorrect);
+
+    let warning = CompilerMessage::new(Level::Warning,
+                                       String::from("unused function"),
+                                       span_bar,
+                                       None,
+                                       cm.clone());
+    let again = CompilerMessage::new(Level::Error,
+                                     String::from("can't compare `&str` with `<T>` again"),
+                                     span_lt,
+                                     Some(String::from("E0369")),
+                                     cm.clone());
+    let help = CompilerMessage::new(Level::Help,
+                                    String::from("no span"),
+                                    DUMMY_SP,
+                                    None,
+                                    cm.clone());
+    let note = CompilerMessage::new(Level::Note, String::from("defined here"), span_a, None, cm);
+    vec![error, warning, again, help, note]
+}
+
+/// A fake environment with `vars` set, for the functions which are given
+/// a lookup instead of reading the process environment.
+pub fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<OsString> {
+    let vars: Vec<(String, OsString)> = vars.iter()
+        .map(|&(k, v)| (k.to_owned(), OsString::from(v)))
+        .collect();
+    move |var: &str| vars.iter().find(|&(k, _)| k == var).map(|(_, v)| v.clone())
+}
+
+/// A writer into a buffer the test keeps a handle on, for emitters which
+/// take their destination by value.
+#[derive(Clone, Default)]
+pub struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    /// Everything written so far.
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file loader over in-memory files, shared with the test so it can look
+/// at what was written.
+#[derive(Clone, Default)]
+pub struct MemLoader(Rc<RefCell<HashMap<String, String>>>);
+
+impl MemLoader {
+    pub fn insert(&self, path: &str, contents: &str) {
+        self.0.borrow_mut().insert(path.to_owned(), contents.to_owned());
+    }
+
+    /// The contents of the file at `path`, which has to exist.
+    pub fn contents(&self, path: &str) -> String {
+        self.0.borrow()[path].clone()
+    }
+}
+
+impl FileLoader for MemLoader {
+    fn file_exists(&self, path: &Path) -> bool {
+        self.0.borrow().contains_key(path.to_str().unwrap())
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.0
+            .borrow()
+            .get(path.to_str().unwrap())
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))
+    }
+
+    fn write_file(&self, path: &Path, contents: &str) -> io::Result<()> {
+        self.insert(path.to_str().unwrap(), contents);
+        Ok(())
+    }
+}
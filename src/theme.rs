@@ -0,0 +1,741 @@
+//! Themes, which decide how each `Style` of the rendered lines looks on a
+//! terminal. The lines themselves stay free of escapes, so they can be
+//! colored by any theme, or turned into HTML instead.
+//!
+//! Besides the built-in themes, a theme can be loaded from a file of
+//! sections named after the styles, which reads as both TOML and INI, eg)
+//!
+//! ```text
+//! # Start from another theme, rather than the default one
+//! base = "monochrome-bold"
+//!
+//! [line-number]
+//! fg = "bright-blue"
+//! bold = true
+//!
+//! [level.error]
+//! fg = "#d62828"
+//! bg = "236"
+//! bold = true
+//! underline = true
+//! ```
+//!
+//! The styles go by `Style::name`, like the CSS classes of the `html` module,
+//! with `level`, `underline-primary` and `label-primary` also taking the name
+//! of a level after a dot, since they're colored by the level of the message.
+//! Colors are one of the 16 terminal colors by name, an index in the
+//! 256-color palette, or `#rrggbb`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use styled_buffer::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Color {
+    /// One of the 16 colors of the terminal, which picks the shades, with
+    /// the bright ones from 8 on.
+    Ansi(u8),
+    /// An index in the 256-color palette.
+    Fixed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// How many colors a terminal shows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TextStyle {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub underline: bool,
+    pub italic: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    /// By the names of the styles, with those of level styles ending in the
+    /// name of the level.
+    styles: BTreeMap<String, TextStyle>,
+}
+
+/// A theme file which couldn't be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThemeError {
+    /// 1-based.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ThemeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ThemeError {}
+
+const COLOR_NAMES: [&str; 16] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan",
+                                 "white", "bright-black", "bright-red", "bright-green",
+                                 "bright-yellow", "bright-blue", "bright-magenta", "bright-cyan",
+                                 "bright-white"];
+
+/// The usual shades of the 16 colors, to find the closest to other colors.
+const ANSI_RGB: [(u8, u8, u8); 16] = [(0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+                                      (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+                                      (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+                                      (92, 92, 255), (255, 0, 255), (0, 255, 255),
+                                      (255, 255, 255)];
+
+/// The levels of the 6x6x6 color cube of the 256-color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// A style of each name.
+const STYLES: [Style; 15] = [Style::HeaderMsg,
+                             Style::FileNameStyle,
+                             Style::LineAndColumn,
+                             Style::LineNumber,
+                             Style::Quotation,
+                             Style::UnderlinePrimary,
+                             Style::UnderlineSecondary,
+                             Style::LabelPrimary,
+                             Style::LabelSecondary,
+                             Style::OldSchoolNoteText,
+                             Style::OldSchoolNote,
+                             Style::NoStyle,
+                             Style::ErrorCode,
+                             Style::Addition,
+                             Style::Level(Level::Error)];
+
+/// A level of each name, but for `Cancelled`, which is never shown.
+const LEVELS: [Level; 5] = [Level::Error, Level::Warning, Level::Note, Level::Help,
+                            Level::FailureNote];
+
+/// The styles colored by the level of the message.
+const LEVEL_STYLES: [Style; 3] = [Style::Level(Level::Error),
+                                  Style::UnderlinePrimary,
+                                  Style::LabelPrimary];
+
+impl Color {
+    /// Parses a color name, palette index or `#rrggbb`.
+    pub fn parse(s: &str) -> Option<Color> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix('#') {
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+        }
+        if let Ok(index) = s.parse::<u8>() {
+            return Some(if index < 16 { Color::Ansi(index) } else { Color::Fixed(index) });
+        }
+        let name = s.to_ascii_lowercase().replace('_', "-");
+        COLOR_NAMES.iter().position(|&n| n == name).map(|i| Color::Ansi(i as u8))
+    }
+
+    /// The color, or the closest one a terminal of `depth` shows.
+    pub fn with_depth(self, depth: ColorDepth) -> Color {
+        match (self, depth) {
+            (Color::Fixed(index), ColorDepth::Ansi16) if index < 16 => Color::Ansi(index),
+            (Color::Fixed(_), ColorDepth::Ansi16) |
+            (Color::Rgb(..), ColorDepth::Ansi16) => {
+                let rgb = self.to_rgb();
+                Color::Ansi(closest(rgb, (0..16).map(|i| (i, ANSI_RGB[i as usize]))))
+            }
+            (Color::Rgb(..), ColorDepth::Ansi256) => {
+                // The first 16 are left out, as terminals pick their shades
+                let rgb = self.to_rgb();
+                Color::Fixed(closest(rgb, (16..=255).map(|i| (i, Color::Fixed(i).to_rgb()))))
+            }
+            _ => self,
+        }
+    }
+
+    /// The usual shade of the color.
+    pub fn to_rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Ansi(index) => ANSI_RGB[index as usize % 16],
+            Color::Fixed(index) if index < 16 => ANSI_RGB[index as usize],
+            Color::Fixed(index) if index < 232 => {
+                let i = (index - 16) as usize;
+                (CUBE_LEVELS[i / 36], CUBE_LEVELS[i / 6 % 6], CUBE_LEVELS[i % 6])
+            }
+            Color::Fixed(index) => {
+                let gray = 8 + 10 * (index - 232);
+                (gray, gray, gray)
+            }
+            Color::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    /// The parameters of the SGR escape setting the color.
+    fn sgr(self, background: bool) -> String {
+        let base = if background { 40 } else { 30 };
+        match self {
+            Color::Ansi(index) if index < 8 => format!("{}", base + index as u32),
+            Color::Ansi(index) => format!("{}", base + 60 + (index % 16 - 8) as u32),
+            Color::Fixed(index) => format!("{};5;{}", base + 8, index),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
+        }
+    }
+
+    /// The escape sequence setting the color.
+    pub fn ansi_escape(self, background: bool) -> String {
+        format!("\x1b[{}m", self.sgr(background))
+    }
+}
+
+/// The value of the color closest to `rgb`.
+fn closest<I: Iterator<Item = (u8, (u8, u8, u8))>>(rgb: (u8, u8, u8), colors: I) -> u8 {
+    let distance = |other: (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(rgb.0, other.0) + d(rgb.1, other.1) + d(rgb.2, other.2)
+    };
+    colors.min_by_key(|&(_, other)| distance(other)).map_or(0, |(value, _)| value)
+}
+
+impl ColorDepth {
+    /// Goes by `COLORTERM`, which terminals with true color set, and then
+    /// by the name of the terminal in `TERM`.
+    pub fn detect() -> ColorDepth {
+        ColorDepth::detect_with_env(|var| env::var_os(var))
+    }
+
+    fn detect_with_env<F>(env_var: F) -> ColorDepth
+        where F: Fn(&str) -> Option<OsString>
+    {
+        let var = |name| env_var(name).map(|val| val.to_string_lossy().into_owned());
+        match var("COLORTERM") {
+            Some(ref val) if val == "truecolor" || val == "24bit" => return ColorDepth::TrueColor,
+            _ => {}
+        }
+        match var("TERM") {
+            Some(ref val) if val.contains("256color") => ColorDepth::Ansi256,
+            _ => ColorDepth::Ansi16,
+        }
+    }
+}
+
+impl TextStyle {
+    pub fn new() -> TextStyle {
+        TextStyle::default()
+    }
+
+    pub fn fg(mut self, color: Color) -> TextStyle {
+        self.fg = Some(color);
+        self
+    }
+
+    pub fn bg(mut self, color: Color) -> TextStyle {
+        self.bg = Some(color);
+        self
+    }
+
+    pub fn bold(mut self) -> TextStyle {
+        self.bold = true;
+        self
+    }
+
+    pub fn underline(mut self) -> TextStyle {
+        self.underline = true;
+        self
+    }
+
+    pub fn italic(mut self) -> TextStyle {
+        self.italic = true;
+        self
+    }
+
+    pub fn is_plain(&self) -> bool {
+        *self == TextStyle::default()
+    }
+
+    /// The escape sequences setting the style on a terminal of `depth`, empty
+    /// for plain text.
+    pub fn ansi_escape(&self, depth: ColorDepth) -> String {
+        let mut escape = String::new();
+        if self.bold {
+            escape.push_str("\x1b[1m");
+        }
+        if self.italic {
+            escape.push_str("\x1b[3m");
+        }
+        if self.underline {
+            escape.push_str("\x1b[4m");
+        }
+        if let Some(fg) = self.fg {
+            escape.push_str(&fg.with_depth(depth).ansi_escape(false));
+        }
+        if let Some(bg) = self.bg {
+            escape.push_str(&bg.with_depth(depth).ansi_escape(true));
+        }
+        escape
+    }
+}
+
+fn is_style_name(name: &str) -> bool {
+    if STYLES.iter().any(|style| style.name() == name) {
+        return true;
+    }
+    match name.split_once('.') {
+        Some((style, level)) => {
+            LEVEL_STYLES.iter().any(|s| s.name() == style) &&
+            LEVELS.iter().any(|l| l.name() == level)
+        }
+        None => false,
+    }
+}
+
+impl Default for Theme {
+    /// The colors rustc uses. Every kind of error has the bold red of
+    /// `level.error`, where only `Error` used to be colored.
+    fn default() -> Theme {
+        let mut theme = Theme::empty();
+        let blue = TextStyle::new().bold().fg(Color::Ansi(12));
+        let green = TextStyle::new().bold().fg(Color::Ansi(10));
+        theme.set("header-msg", TextStyle::new().bold());
+        theme.set("old-school-note-text", TextStyle::new().bold());
+        theme.set("line-number", blue);
+        theme.set("underline-secondary", blue);
+        theme.set("label-secondary", blue);
+        theme.set("error-code", TextStyle::new().bold().fg(Color::Ansi(13)));
+        theme.set("addition", green);
+        theme.set("old-school-note", green);
+        theme.set("level.error", TextStyle::new().bold().fg(Color::Ansi(9)));
+        theme.set("level.warning", TextStyle::new().bold().fg(Color::Ansi(3)));
+        theme.set("level.note", TextStyle::new().bold());
+        theme.set_primary(&[Color::Ansi(9), Color::Ansi(3), Color::Ansi(10), Color::Ansi(14),
+                            Color::Ansi(15)],
+                          TextStyle::new().bold());
+        theme
+    }
+}
+
+impl Theme {
+    /// A theme leaving every style plain.
+    pub fn empty() -> Theme {
+        Theme { styles: BTreeMap::new() }
+    }
+
+    /// Bright text, with the levels set on colored backgrounds, for low
+    /// vision and washed out screens.
+    pub fn high_contrast() -> Theme {
+        let mut theme = Theme::empty();
+        let white = TextStyle::new().bold().fg(Color::Ansi(15));
+        let cyan = TextStyle::new().bold().fg(Color::Ansi(14));
+        theme.set("header-msg", white);
+        theme.set("old-school-note-text", white);
+        theme.set("line-number", cyan);
+        theme.set("underline-secondary", cyan);
+        theme.set("label-secondary", cyan);
+        theme.set("error-code", TextStyle::new().bold().fg(Color::Ansi(13)));
+        theme.set("addition", TextStyle::new().bold().fg(Color::Ansi(10)));
+        theme.set("old-school-note", TextStyle::new().bold().fg(Color::Ansi(10)));
+        theme.set("level.error", white.bg(Color::Ansi(1)));
+        theme.set("level.warning", TextStyle::new().bold().fg(Color::Ansi(0)).bg(Color::Ansi(11)));
+        theme.set("level.note", TextStyle::new().bold().fg(Color::Ansi(0)).bg(Color::Ansi(10)));
+        theme.set("level.help", TextStyle::new().bold().fg(Color::Ansi(0)).bg(Color::Ansi(14)));
+        theme.set("level.failure-note", white);
+        theme.set_primary(&[Color::Ansi(9), Color::Ansi(11), Color::Ansi(10), Color::Ansi(14),
+                            Color::Ansi(15)],
+                          TextStyle::new().bold().underline());
+        theme
+    }
+
+    /// The Okabe-Ito colors, which stay apart for the common kinds of color
+    /// blindness, with errors in vermillion rather than red.
+    pub fn colorblind() -> Theme {
+        let vermillion = Color::Rgb(213, 94, 0);
+        let orange = Color::Rgb(230, 159, 0);
+        let sky_blue = Color::Rgb(86, 180, 233);
+        let bluish_green = Color::Rgb(0, 158, 115);
+        let blue = Color::Rgb(0, 114, 178);
+        let purple = Color::Rgb(204, 121, 167);
+
+        let mut theme = Theme::empty();
+        theme.set("header-msg", TextStyle::new().bold());
+        theme.set("old-school-note-text", TextStyle::new().bold());
+        theme.set("line-number", TextStyle::new().bold().fg(blue));
+        theme.set("underline-secondary", TextStyle::new().bold().fg(sky_blue));
+        theme.set("label-secondary", TextStyle::new().bold().fg(sky_blue));
+        theme.set("error-code", TextStyle::new().bold().fg(purple));
+        theme.set("addition", TextStyle::new().bold().fg(bluish_green));
+        theme.set("old-school-note", TextStyle::new().bold().fg(bluish_green));
+        theme.set("level.error", TextStyle::new().bold().fg(vermillion));
+        theme.set("level.warning", TextStyle::new().bold().fg(orange));
+        theme.set("level.note", TextStyle::new().bold());
+        // Underlined as well, for when the colors are told apart by shade
+        theme.set_primary(&[vermillion, orange, bluish_green, sky_blue, Color::Ansi(15)],
+                          TextStyle::new().bold());
+        theme.set("underline-primary.error",
+                  TextStyle::new().bold().underline().fg(vermillion));
+        theme
+    }
+
+    /// No colors at all, with the levels and primary spans told apart by
+    /// bold, underlined and italic text.
+    pub fn monochrome_bold() -> Theme {
+        let mut theme = Theme::empty();
+        let bold = TextStyle::new().bold();
+        theme.set("header-msg", bold);
+        theme.set("old-school-note-text", bold);
+        theme.set("line-number", bold);
+        theme.set("error-code", bold);
+        theme.set("addition", bold.underline());
+        theme.set("old-school-note", bold);
+        theme.set("underline-primary", bold);
+        theme.set("label-primary", bold);
+        theme.set("underline-secondary", TextStyle::new());
+        theme.set("label-secondary", TextStyle::new().italic());
+        theme.set("level", bold);
+        theme.set("level.error", bold.underline());
+        theme.set("level.warning", bold.italic());
+        theme
+    }
+
+    /// The names of the built-in themes.
+    pub fn builtin_names() -> &'static [&'static str] {
+        &["default", "high-contrast", "colorblind", "monochrome-bold"]
+    }
+
+    pub fn builtin(name: &str) -> Option<Theme> {
+        match name {
+            "default" => Some(Theme::default()),
+            "high-contrast" => Some(Theme::high_contrast()),
+            "colorblind" => Some(Theme::colorblind()),
+            "monochrome-bold" => Some(Theme::monochrome_bold()),
+            _ => None,
+        }
+    }
+
+    /// Sets the look of a style by its name, eg) `line-number` or
+    /// `level.warning`.
+    pub fn set(&mut self, name: &str, style: TextStyle) {
+        self.styles.insert(name.to_owned(), style);
+    }
+
+    /// Sets the primary underlines and labels of each level, in the order of
+    /// `LEVELS`, to `style` in its color.
+    fn set_primary(&mut self, colors: &[Color; 5], style: TextStyle) {
+        for (level, &color) in LEVELS.iter().map(|level| level.name()).zip(colors) {
+            self.set(&format!("underline-primary.{}", level), style.fg(color));
+            self.set(&format!("label-primary.{}", level), style.fg(color));
+        }
+    }
+
+    /// The look of `style` in a message of `level`.
+    pub fn style(&self, style: Style, level: Level) -> TextStyle {
+        let name = style.name();
+        let level = match style {
+            Style::Level(level) => level,
+            _ => level,
+        };
+        if LEVEL_STYLES.iter().any(|s| s.name() == name) {
+            let key = format!("{}.{}", name, level.name());
+            if let Some(&text_style) = self.styles.get(&key) {
+                return text_style;
+            }
+        }
+        self.styles.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Renders styled lines with the escapes of a terminal of `depth`.
+    pub fn render_ansi(&self,
+                       level: Level,
+                       lines: &[Vec<StyledString>],
+                       depth: ColorDepth)
+                       -> String {
+        let mut output = String::new();
+        for line in lines {
+            for part in line {
+                let escape = self.style(part.style, level).ansi_escape(depth);
+                if escape.is_empty() {
+                    output.push_str(&part.text);
+                } else {
+                    write!(output, "{}{}\x1b[0m", escape, part.text).unwrap();
+                }
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Reads the theme file at `path`, with `parse`.
+    pub fn load(path: &Path) -> io::Result<Theme> {
+        let src = fs::read_to_string(path)?;
+        Theme::parse(&src).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Reads a theme file. The styles it names replace those of its base
+    /// theme, the default one unless it says otherwise.
+    pub fn parse(src: &str) -> Result<Theme, ThemeError> {
+        let mut theme = Theme::default();
+        // The style being read, by name
+        let mut section: Option<(String, TextStyle)> = None;
+
+        for (i, line) in src.lines().enumerate() {
+            let error = |message: String| {
+                ThemeError {
+                    line: i + 1,
+                    message: message,
+                }
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name.strip_suffix(']')
+                    .ok_or_else(|| error(String::from("expected `]`")))?
+                    .trim()
+                    .trim_matches('"');
+                if !is_style_name(name) {
+                    return Err(error(format!("unknown style `{}`", name)));
+                }
+                if let Some((name, style)) = section.take() {
+                    theme.set(&name, style);
+                }
+                section = Some((name.to_owned(), TextStyle::new()));
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| error(String::from("expected `key = value`")))?;
+            let key = key.trim();
+            let value = unquote(strip_comment(value).trim());
+
+            let style = match section {
+                Some((_, ref mut style)) => style,
+                None if key == "base" => {
+                    theme = Theme::builtin(value)
+                        .ok_or_else(|| error(format!("unknown theme `{}`", value)))?;
+                    continue;
+                }
+                None => return Err(error(format!("unknown setting `{}`", key))),
+            };
+            let flag = || {
+                match value {
+                    "true" => Ok(true),
+                    "false" => Ok(false),
+                    _ => Err(error(format!("expected `true` or `false`, found `{}`", value))),
+                }
+            };
+            let color = || {
+                if value == "none" {
+                    return Ok(None);
+                }
+                Color::parse(value)
+                    .map(Some)
+                    .ok_or_else(|| error(format!("unknown color `{}`", value)))
+            };
+            match key {
+                "fg" => style.fg = color()?,
+                "bg" => style.bg = color()?,
+                "bold" => style.bold = flag()?,
+                "underline" => style.underline = flag()?,
+                "italic" => style.italic = flag()?,
+                _ => return Err(error(format!("unknown attribute `{}`", key))),
+            }
+        }
+
+        if let Some((name, style)) = section {
+            theme.set(&name, style);
+        }
+        Ok(theme)
+    }
+}
+
+/// Drops a comment after a value, leaving any `#` within quotes.
+fn strip_comment(value: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted && value[..i].ends_with(char::is_whitespace) => {
+                return &value[..i]
+            }
+            _ => {}
+        }
+    }
+    value
+}
+
+fn unquote(value: &str) -> &str {
+    value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_utils::env;
+
+    #[test]
+    fn colors() {
+        assert_eq!(Color::parse("bright-blue"), Some(Color::Ansi(12)));
+        assert_eq!(Color::parse("Bright_Red"), Some(Color::Ansi(9)));
+        assert_eq!(Color::parse("3"), Some(Color::Ansi(3)));
+        assert_eq!(Color::parse("208"), Some(Color::Fixed(208)));
+        assert_eq!(Color::parse("#d55e00"), Some(Color::Rgb(213, 94, 0)));
+        assert_eq!(Color::parse("#d5e00"), None);
+        assert_eq!(Color::parse("256"), None);
+        assert_eq!(Color::parse("mauve"), None);
+
+        let vermillion = Color::Rgb(213, 94, 0);
+        assert_eq!(vermillion.with_depth(ColorDepth::TrueColor), vermillion);
+        assert_eq!(vermillion.with_depth(ColorDepth::Ansi256), Color::Fixed(166));
+        assert_eq!(vermillion.with_depth(ColorDepth::Ansi16), Color::Ansi(1));
+        assert_eq!(Color::Fixed(244).with_depth(ColorDepth::Ansi16), Color::Ansi(8));
+        assert_eq!(Color::Fixed(9).with_depth(ColorDepth::Ansi16), Color::Ansi(9));
+        assert_eq!(Color::Ansi(3).with_depth(ColorDepth::Ansi16), Color::Ansi(3));
+        assert_eq!(Color::Fixed(166).to_rgb(), (215, 95, 0));
+        assert_eq!(Color::Fixed(232).to_rgb(), (8, 8, 8));
+
+        assert_eq!(Color::Ansi(3).ansi_escape(false), "\x1b[33m");
+        assert_eq!(Color::Ansi(12).ansi_escape(true), "\x1b[104m");
+        assert_eq!(Color::Fixed(166).ansi_escape(false), "\x1b[38;5;166m");
+        assert_eq!(vermillion.ansi_escape(true), "\x1b[48;2;213;94;0m");
+    }
+
+    #[test]
+    fn color_depth() {
+        let detect = |vars: &[(&str, &str)]| ColorDepth::detect_with_env(env(vars));
+        assert_eq!(detect(&[]), ColorDepth::Ansi16);
+        assert_eq!(detect(&[("TERM", "xterm")]), ColorDepth::Ansi16);
+        assert_eq!(detect(&[("TERM", "xterm-256color")]), ColorDepth::Ansi256);
+        assert_eq!(detect(&[("TERM", "xterm-256color"), ("COLORTERM", "truecolor")]),
+                   ColorDepth::TrueColor);
+        assert_eq!(detect(&[("COLORTERM", "24bit")]), ColorDepth::TrueColor);
+        assert_eq!(detect(&[("COLORTERM", "yes")]), ColorDepth::Ansi16);
+    }
+
+    #[test]
+    fn text_style_escapes() {
+        let style = TextStyle::new().bold().italic().underline().fg(Color::Rgb(0, 114, 178));
+        assert_eq!(style.ansi_escape(ColorDepth::TrueColor),
+                   "\x1b[1m\x1b[3m\x1b[4m\x1b[38;2;0;114;178m");
+        assert_eq!(style.ansi_escape(ColorDepth::Ansi256),
+                   "\x1b[1m\x1b[3m\x1b[4m\x1b[38;5;25m");
+        assert_eq!(TextStyle::new().bg(Color::Ansi(1)).ansi_escape(ColorDepth::Ansi16),
+                   "\x1b[41m");
+        assert!(TextStyle::new().is_plain());
+        assert_eq!(TextStyle::new().ansi_escape(ColorDepth::TrueColor), "");
+    }
+
+    #[test]
+    fn builtin_themes() {
+        for name in Theme::builtin_names() {
+            assert!(Theme::builtin(name).is_some(), "{}", name);
+        }
+        assert!(Theme::builtin("solarized").is_none());
+
+        let theme = Theme::default();
+        assert_eq!(theme.style(Style::LineNumber, Level::Error),
+                   TextStyle::new().bold().fg(Color::Ansi(12)));
+        // The primary spans and levels take the color of the level
+        assert_eq!(theme.style(Style::UnderlinePrimary, Level::Warning),
+                   TextStyle::new().bold().fg(Color::Ansi(3)));
+        assert_eq!(theme.style(Style::LabelPrimary, Level::Bug),
+                   TextStyle::new().bold().fg(Color::Ansi(9)));
+        assert_eq!(theme.style(Style::Level(Level::Warning), Level::Error),
+                   TextStyle::new().bold().fg(Color::Ansi(3)));
+        assert!(theme.style(Style::Level(Level::Help), Level::Help).is_plain());
+        assert!(theme.style(Style::NoStyle, Level::Error).is_plain());
+
+        // Falling back on the style for every level
+        let mono = Theme::monochrome_bold();
+        assert_eq!(mono.style(Style::Level(Level::Help), Level::Help), TextStyle::new().bold());
+        assert_eq!(mono.style(Style::Level(Level::Error), Level::Help),
+                   TextStyle::new().bold().underline());
+        for name in Theme::builtin_names() {
+            let theme = Theme::builtin(name).unwrap();
+            if *name == "monochrome-bold" {
+                assert!(theme.styles.values().all(|style| style.fg.is_none()));
+            } else {
+                assert!(theme.styles.values().any(|style| style.fg.is_some()));
+            }
+        }
+    }
+
+    #[test]
+    fn render_ansi() {
+        let lines = vec![vec![StyledString {
+                                  text: String::from("error"),
+                                  style: Style::Level(Level::Error),
+                              },
+                              StyledString {
+                                  text: String::from(": oops"),
+                                  style: Style::NoStyle,
+                              }]];
+        let theme = Theme::colorblind();
+        assert_eq!(theme.render_ansi(Level::Error, &lines, ColorDepth::TrueColor),
+                   "\x1b[1m\x1b[38;2;213;94;0merror\x1b[0m: oops\n");
+        assert_eq!(theme.render_ansi(Level::Error, &lines, ColorDepth::Ansi256),
+                   "\x1b[1m\x1b[38;5;166merror\x1b[0m: oops\n");
+        assert_eq!(Theme::empty().render_ansi(Level::Error, &lines, ColorDepth::TrueColor),
+                   make_string(&lines));
+    }
+
+    #[test]
+    fn parse_theme_files() {
+        let toml = r##"
+# Errors on a dark background
+base = "monochrome-bold"
+
+[line-number]
+fg = "bright-blue"   # like rustc
+bold = true
+
+["level.error"]
+fg = "#d62828"
+bg = "236"
+underline = true
+"##;
+        let theme = Theme::parse(toml).unwrap();
+        assert_eq!(theme.style(Style::LineNumber, Level::Note),
+                   TextStyle::new().bold().fg(Color::Ansi(12)));
+        assert_eq!(theme.style(Style::Level(Level::Error), Level::Error),
+                   TextStyle::new().underline().fg(Color::Rgb(214, 40, 40)).bg(Color::Fixed(236)));
+        // The rest comes from the base theme
+        assert_eq!(theme.style(Style::Level(Level::Warning), Level::Error),
+                   TextStyle::new().bold().italic());
+
+        let ini = "\
+; Only changes the warnings
+[level.warning]
+fg = yellow
+italic = true
+";
+        let theme = Theme::parse(ini).unwrap();
+        assert_eq!(theme.style(Style::Level(Level::Warning), Level::Error),
+                   TextStyle::new().italic().fg(Color::Ansi(3)));
+        assert_eq!(theme.style(Style::LineNumber, Level::Note),
+                   Theme::default().style(Style::LineNumber, Level::Note));
+
+        let error = |src: &str| Theme::parse(src).unwrap_err().to_string();
+        assert_eq!(error("[line-numbers]"), "line 1: unknown style `line-numbers`");
+        assert_eq!(error("[level.fatal]"), "line 1: unknown style `level.fatal`");
+        assert_eq!(error("\n[addition]\nfg = mauve"), "line 3: unknown color `mauve`");
+        assert_eq!(error("[addition]\nbold = 1"),
+                   "line 2: expected `true` or `false`, found `1`");
+        assert_eq!(error("[addition]\nblink = true"), "line 2: unknown attribute `blink`");
+        assert_eq!(error("base = solarized"), "line 1: unknown theme `solarized`");
+        assert_eq!(error("fg = red"), "line 1: unknown setting `fg`");
+        assert_eq!(error("[addition"), "line 1: expected `]`");
+        assert_eq!(error("[addition]\nbold"), "line 2: expected `key = value`");
+    }
+}